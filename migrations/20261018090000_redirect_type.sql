@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE urls
+ADD COLUMN redirect_type SMALLINT NOT NULL DEFAULT 308;
@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    -- SHA-256 of the key; the plaintext is only shown once when the key is created
+    key_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
+
+ALTER TABLE urls
+ADD COLUMN owner_key_id BIGINT;
+
+CREATE INDEX urls_owner_key_id_idx ON urls (owner_key_id);
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE api_keys
+ADD COLUMN tier TEXT NOT NULL DEFAULT 'default',
+ADD COLUMN daily_quota BIGINT;
@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE clicks (
+    short_code VARCHAR(50) NOT NULL,
+    clicked_at TIMESTAMPTZ NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    country CHAR(2),
+    -- Hash of client IP and user agent, for counting unique visitors without storing the IP
+    visitor_hash BIGINT NOT NULL
+) PARTITION BY RANGE (clicked_at);
+
+-- Monthly partitions (clicks_yYYYYmMM) are created by the click writer; this
+-- catches anything written before its month's partition exists
+CREATE TABLE clicks_default PARTITION OF clicks DEFAULT;
+
+CREATE INDEX clicks_short_code_clicked_at_idx ON clicks (short_code, clicked_at);
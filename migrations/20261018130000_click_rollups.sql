@@ -0,0 +1,27 @@
+-- Add migration script here
+-- Clicks per link and hour, with a HyperLogLog sketch of the visitors in that hour
+CREATE TABLE click_rollups_hourly (
+    short_code VARCHAR(50) NOT NULL,
+    bucket TIMESTAMPTZ NOT NULL,
+    clicks BIGINT NOT NULL,
+    visitors BYTEA NOT NULL,
+    PRIMARY KEY (short_code, bucket)
+);
+
+-- Clicks per link, day and referrer / user agent
+CREATE TABLE click_rollups_dimension (
+    short_code VARCHAR(50) NOT NULL,
+    day DATE NOT NULL,
+    dimension TEXT NOT NULL,
+    value TEXT NOT NULL,
+    clicks BIGINT NOT NULL,
+    PRIMARY KEY (short_code, day, dimension, value)
+);
+
+-- Clicks before this instant have been rolled up
+CREATE TABLE click_rollup_state (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    rolled_up_to TIMESTAMPTZ NOT NULL
+);
+
+INSERT INTO click_rollup_state (rolled_up_to) VALUES ('1970-01-01 00:00:00+00');
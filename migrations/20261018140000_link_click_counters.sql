@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Live click counts per link and day, flushed from the Redis counters
+CREATE TABLE link_click_counters (
+    short_code VARCHAR(50) NOT NULL,
+    day DATE NOT NULL,
+    clicks BIGINT NOT NULL,
+    PRIMARY KEY (short_code, day)
+);
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Exact expiry for links that end partway through a day. expiry_date stays the
+-- partition key (the UTC date of expires_at); NULL means live through the end
+-- of expiry_date.
+ALTER TABLE urls
+ADD COLUMN expires_at TIMESTAMPTZ;
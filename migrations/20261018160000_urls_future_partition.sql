@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Links expiring beyond the monthly partition window, including links that
+-- never expire. Rows move into a monthly partition when it is created.
+CREATE TABLE IF NOT EXISTS urls_future PARTITION OF urls DEFAULT;
@@ -0,0 +1,43 @@
+-- Add migration script here
+-- Tell running instances about link changes made outside CargoCut, e.g. by an
+-- operator or another service, so they can drop cached copies and fix their
+-- filters. CargoCut's own sessions set application_name = 'cargocut' and
+-- handle their changes themselves.
+CREATE OR REPLACE FUNCTION notify_urls_changed() RETURNS trigger AS $$
+DECLARE
+    payload json;
+BEGIN
+    IF current_setting('application_name', true) = 'cargocut' THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'INSERT' THEN
+        payload := json_build_object(
+            'op', TG_OP,
+            'short_code', NEW.short_code,
+            'new_expiry_date', NEW.expiry_date
+        );
+    ELSIF TG_OP = 'DELETE' THEN
+        payload := json_build_object(
+            'op', TG_OP,
+            'short_code', OLD.short_code,
+            'old_expiry_date', OLD.expiry_date
+        );
+    ELSE
+        payload := json_build_object(
+            'op', TG_OP,
+            'short_code', NEW.short_code,
+            'old_expiry_date', OLD.expiry_date,
+            'new_expiry_date', NEW.expiry_date
+        );
+    END IF;
+
+    PERFORM pg_notify('urls_changed', payload::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- An UPDATE that moves a row to another partition fires DELETE and INSERT instead
+CREATE TRIGGER urls_changed
+AFTER INSERT OR UPDATE OR DELETE ON urls
+FOR EACH ROW EXECUTE FUNCTION notify_urls_changed();
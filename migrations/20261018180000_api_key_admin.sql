@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Admin keys may also call the /api/admin endpoints
+ALTER TABLE api_keys
+ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT false;
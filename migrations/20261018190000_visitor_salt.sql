@@ -0,0 +1,10 @@
+-- Add migration script here
+-- Salt for click visitor hashes, generated once per deployment and shared by
+-- every instance; analytics.visitor_salt overrides it
+CREATE TABLE analytics_settings (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    visitor_salt TEXT NOT NULL
+);
+
+INSERT INTO analytics_settings (visitor_salt)
+VALUES (replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', ''));
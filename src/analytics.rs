@@ -0,0 +1,538 @@
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::hll::HyperLogLog;
+use crate::metrics::{CLICKS_DROPPED, CLICKS_WRITTEN};
+use crate::redis::RedisManager;
+use crate::shutdown::Shutdown;
+
+// Longest referrer / user agent kept; anything past this is noise
+const MAX_HEADER_LEN: usize = 512;
+
+// A redirect as it is queued on the hot path; country and visitor hash are
+// worked out by the writer
+#[derive(Debug)]
+pub struct ClickEvent {
+    pub short_code: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub client_ip: IpAddr,
+}
+
+// Cheap handle for queueing clicks from request handlers
+#[derive(Clone)]
+pub struct ClickRecorder {
+    tx: Option<mpsc::Sender<ClickEvent>>,
+    // Live per-link counters are bumped by the writer, once per batch
+    live_counters: bool,
+}
+
+impl ClickRecorder {
+    pub fn new(capacity: usize, live_counters: bool) -> (Self, mpsc::Receiver<ClickEvent>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (
+            ClickRecorder {
+                tx: Some(tx),
+                live_counters,
+            },
+            rx,
+        )
+    }
+
+    // Recorder that drops everything, for when analytics are off
+    pub fn disabled() -> Self {
+        ClickRecorder {
+            tx: None,
+            live_counters: false,
+        }
+    }
+
+    // Whether live per-link counters are kept in Redis
+    pub fn counts_live(&self) -> bool {
+        self.live_counters
+    }
+
+    // Queue a click without waiting; when the queue is full the click is dropped
+    pub fn record(&self, short_code: &str, client_ip: IpAddr, headers: &HeaderMap) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        let event = ClickEvent {
+            short_code: short_code.to_string(),
+            clicked_at: Utc::now(),
+            referrer: header_value(headers, header::REFERER),
+            user_agent: header_value(headers, header::USER_AGENT),
+            client_ip,
+        };
+
+        match tx.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => CLICKS_DROPPED.inc(),
+        }
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let end = value
+        .char_indices()
+        .nth(MAX_HEADER_LEN)
+        .map_or(value.len(), |(i, _)| i);
+    Some(value[..end].to_string())
+}
+
+// Country ranges loaded from a `start_ip,end_ip,country` CSV, IPv4 stored as mapped IPv6
+pub struct GeoIpDb {
+    ranges: Vec<(u128, u128, String)>,
+}
+
+impl GeoIpDb {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut ranges = Vec::new();
+
+        for (line_no, line) in contents.lines().enumerate() {
+            let fields: Vec<&str> = line
+                .split(',')
+                .map(|f| f.trim().trim_matches('"'))
+                .collect();
+            if fields.len() < 3 || fields[0].is_empty() {
+                continue;
+            }
+            let (Ok(start), Ok(end)) = (fields[0].parse::<IpAddr>(), fields[1].parse::<IpAddr>())
+            else {
+                // Header rows and comments
+                if line_no == 0 {
+                    continue;
+                }
+                return Err(format!("{}:{}: invalid IP range", path, line_no + 1).into());
+            };
+            let country = fields[2].to_ascii_uppercase();
+            if country.len() != 2 {
+                continue;
+            }
+            ranges.push((ip_to_u128(start), ip_to_u128(end), country));
+        }
+
+        ranges.sort_by_key(|(start, _, _)| *start);
+        Ok(GeoIpDb { ranges })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip_to_u128(ip);
+        let idx = self.ranges.partition_point(|(start, _, _)| *start <= ip);
+        let (_, end, country) = self.ranges.get(idx.checked_sub(1)?)?;
+        (ip <= *end).then_some(country.as_str())
+    }
+}
+
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+// The configured visitor salt, or else the one the migrations generated
+pub async fn visitor_salt(pool: &PgPool, configured: &str) -> Result<String, sqlx::Error> {
+    if !configured.is_empty() {
+        return Ok(configured.to_string());
+    }
+    sqlx::query_scalar("SELECT visitor_salt FROM analytics_settings")
+        .fetch_one(pool)
+        .await
+}
+
+// Stable per-visitor id: the same IP and user agent always hash the same. The
+// salt keeps the hash from being reversed by trying every IPv4 address.
+fn visitor_hash(salt: &str, ip: IpAddr, user_agent: Option<&str>) -> i64 {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update([0]);
+    hasher.update(ip.to_string());
+    hasher.update([0]);
+    hasher.update(user_agent.unwrap_or_default());
+    let digest = hasher.finalize();
+    i64::from_be_bytes(digest[..8].try_into().expect("sha256 is 32 bytes"))
+}
+
+// Drain the click queue into Postgres in batches until shutdown, then flush
+// whatever is still queued
+pub async fn run_click_writer(
+    mut rx: mpsc::Receiver<ClickEvent>,
+    mut writer: ClickWriter,
+    batch_size: usize,
+    flush_interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut interval = tokio::time::interval(flush_interval);
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => {
+                    batch.push(event);
+                    if batch.len() >= batch_size {
+                        writer.flush(&mut batch).await;
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick() => {
+                if !batch.is_empty() {
+                    writer.flush(&mut batch).await;
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    // Stop accepting clicks and write out the rest
+    rx.close();
+    while let Some(event) = rx.recv().await {
+        batch.push(event);
+        if batch.len() >= batch_size {
+            writer.flush(&mut batch).await;
+        }
+    }
+    if !batch.is_empty() {
+        writer.flush(&mut batch).await;
+    }
+}
+
+// Writes batches of clicks to Postgres and the live counters
+pub struct ClickWriter {
+    pool: PgPool,
+    geoip: Option<GeoIpDb>,
+    visitor_salt: String,
+    // Redis for the live per-link counters, when they are on
+    counters: Option<RedisManager>,
+    // Months whose `clicks_yYYYYmMM` partition is known to exist
+    partitions: HashSet<NaiveDate>,
+}
+
+impl ClickWriter {
+    pub fn new(
+        pool: PgPool,
+        geoip: Option<GeoIpDb>,
+        visitor_salt: String,
+        counters: Option<RedisManager>,
+    ) -> Self {
+        ClickWriter {
+            pool,
+            geoip,
+            visitor_salt,
+            counters,
+            partitions: HashSet::new(),
+        }
+    }
+
+    async fn flush(&mut self, batch: &mut Vec<ClickEvent>) {
+        let count = batch.len() as u64;
+        self.count_live(batch).await;
+        match self.write(batch).await {
+            Ok(()) => CLICKS_WRITTEN.inc_by(count as f64),
+            Err(e) => {
+                tracing::error!("Failed to write {} clicks: {}", count, e);
+                CLICKS_DROPPED.inc_by(count as f64);
+            }
+        }
+        batch.clear();
+    }
+
+    // Add the batch to the live counters in one pipeline; a failure only makes
+    // the live count lag behind the rollups
+    async fn count_live(&self, batch: &[ClickEvent]) {
+        let Some(redis) = &self.counters else {
+            return;
+        };
+        let mut counts: HashMap<(&str, NaiveDate), i64> = HashMap::new();
+        for event in batch {
+            *counts
+                .entry((event.short_code.as_str(), event.clicked_at.date_naive()))
+                .or_default() += 1;
+        }
+        let counts: Vec<(String, NaiveDate, i64)> = counts
+            .into_iter()
+            .map(|((short_code, day), count)| (short_code.to_string(), day, count))
+            .collect();
+        if let Err(e) = redis.add_click_counts(&counts).await {
+            tracing::warn!("Failed to count {} clicks live: {}", batch.len(), e);
+        }
+    }
+
+    async fn write(&mut self, batch: &[ClickEvent]) -> Result<(), sqlx::Error> {
+        for event in batch {
+            let month = first_of_month(event.clicked_at.date_naive());
+            if !self.partitions.contains(&month) {
+                ensure_clicks_partition(&self.pool, month).await?;
+                self.partitions.insert(month);
+            }
+        }
+
+        let mut short_codes = Vec::with_capacity(batch.len());
+        let mut clicked_at = Vec::with_capacity(batch.len());
+        let mut referrers = Vec::with_capacity(batch.len());
+        let mut user_agents = Vec::with_capacity(batch.len());
+        let mut countries = Vec::with_capacity(batch.len());
+        let mut visitors = Vec::with_capacity(batch.len());
+        for event in batch {
+            short_codes.push(event.short_code.as_str());
+            clicked_at.push(event.clicked_at);
+            referrers.push(event.referrer.as_deref());
+            user_agents.push(event.user_agent.as_deref());
+            countries.push(
+                self.geoip
+                    .as_ref()
+                    .and_then(|geoip| geoip.country(event.client_ip)),
+            );
+            visitors.push(visitor_hash(
+                &self.visitor_salt,
+                event.client_ip,
+                event.user_agent.as_deref(),
+            ));
+        }
+
+        sqlx::query(
+            "INSERT INTO clicks (short_code, clicked_at, referrer, user_agent, country, visitor_hash)
+             SELECT * FROM UNNEST($1::text[], $2::timestamptz[], $3::text[], $4::text[], $5::text[], $6::bigint[])",
+        )
+        .bind(&short_codes)
+        .bind(&clicked_at)
+        .bind(&referrers)
+        .bind(&user_agents)
+        .bind(&countries)
+        .bind(&visitors)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).expect("every month has a first day")
+}
+
+// Create the monthly `clicks` partition starting at `month`
+async fn ensure_clicks_partition(pool: &PgPool, month: NaiveDate) -> Result<(), sqlx::Error> {
+    let end = month
+        .checked_add_months(chrono::Months::new(1))
+        .ok_or_else(|| sqlx::Error::Protocol("click date out of range".into()))?;
+    let query = format!(
+        "CREATE TABLE IF NOT EXISTS clicks_y{}m{:02}
+         PARTITION OF clicks
+         FOR VALUES FROM ('{} 00:00:00+00') TO ('{} 00:00:00+00');",
+        month.year(),
+        month.month(),
+        month,
+        end
+    );
+    sqlx::query(&query).execute(pool).await?;
+    Ok(())
+}
+
+// Fold raw clicks into the rollup tables the stats API reads, every `interval`.
+// Clicks younger than `delay` are left for the next run so late batches aren't missed.
+pub async fn run_click_rollups(
+    pool: PgPool,
+    interval: Duration,
+    delay: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+
+        match roll_up_clicks(&pool, delay).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Rolled up {} clicks", count),
+            Err(e) => tracing::error!("Click rollup failed: {}", e),
+        }
+    }
+}
+
+// Roll up clicks between the stored watermark and `now - delay`, returning how many
+pub async fn roll_up_clicks(pool: &PgPool, delay: Duration) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // The row lock keeps other instances from rolling up the same range
+    let from: DateTime<Utc> =
+        sqlx::query_scalar("SELECT rolled_up_to FROM click_rollup_state FOR UPDATE")
+            .fetch_one(&mut *tx)
+            .await?;
+    let to = Utc::now() - delay;
+    if to <= from {
+        return Ok(0);
+    }
+
+    let hours: Vec<(String, DateTime<Utc>, i64, Vec<i64>)> = sqlx::query_as(
+        "SELECT short_code, date_trunc('hour', clicked_at, 'UTC'), count(*),
+                array_agg(DISTINCT visitor_hash)
+         FROM clicks
+         WHERE clicked_at >= $1 AND clicked_at < $2
+         GROUP BY 1, 2",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut total = 0;
+    if !hours.is_empty() {
+        let short_codes: Vec<&str> = hours.iter().map(|(code, ..)| code.as_str()).collect();
+        let buckets: Vec<DateTime<Utc>> = hours.iter().map(|(_, bucket, ..)| *bucket).collect();
+
+        // Merge into the sketches of hours that were partly rolled up before
+        let existing: Vec<(String, DateTime<Utc>, Vec<u8>)> = sqlx::query_as(
+            "SELECT r.short_code, r.bucket, r.visitors
+             FROM click_rollups_hourly r
+             JOIN UNNEST($1::text[], $2::timestamptz[]) AS k(short_code, bucket)
+               ON r.short_code = k.short_code AND r.bucket = k.bucket",
+        )
+        .bind(&short_codes)
+        .bind(&buckets)
+        .fetch_all(&mut *tx)
+        .await?;
+        let existing: HashMap<(String, DateTime<Utc>), Vec<u8>> = existing
+            .into_iter()
+            .map(|(code, bucket, visitors)| ((code, bucket), visitors))
+            .collect();
+
+        let mut clicks = Vec::with_capacity(hours.len());
+        let mut sketches = Vec::with_capacity(hours.len());
+        for (short_code, bucket, count, visitor_hashes) in &hours {
+            let mut sketch = existing
+                .get(&(short_code.clone(), *bucket))
+                .map(|bytes| HyperLogLog::from_bytes(bytes))
+                .unwrap_or_default();
+            for hash in visitor_hashes {
+                sketch.insert(*hash as u64);
+            }
+            clicks.push(*count);
+            sketches.push(sketch.as_bytes().to_vec());
+            total += *count as u64;
+        }
+
+        sqlx::query(
+            "INSERT INTO click_rollups_hourly (short_code, bucket, clicks, visitors)
+             SELECT * FROM UNNEST($1::text[], $2::timestamptz[], $3::bigint[], $4::bytea[])
+             ON CONFLICT (short_code, bucket) DO UPDATE
+             SET clicks = click_rollups_hourly.clicks + EXCLUDED.clicks,
+                 visitors = EXCLUDED.visitors",
+        )
+        .bind(&short_codes)
+        .bind(&buckets)
+        .bind(&clicks)
+        .bind(&sketches)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO click_rollups_dimension (short_code, day, dimension, value, clicks)
+             SELECT c.short_code, (c.clicked_at AT TIME ZONE 'UTC')::date, d.dimension, d.value, count(*)
+             FROM clicks c
+             CROSS JOIN LATERAL (VALUES
+                 ('referrer', COALESCE(c.referrer, '(direct)')),
+                 ('user_agent', COALESCE(c.user_agent, '(unknown)'))
+             ) AS d(dimension, value)
+             WHERE c.clicked_at >= $1 AND c.clicked_at < $2
+             GROUP BY 1, 2, 3, 4
+             ON CONFLICT (short_code, day, dimension, value) DO UPDATE
+             SET clicks = click_rollups_dimension.clicks + EXCLUDED.clicks",
+        )
+        .bind(from)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    sqlx::query("UPDATE click_rollup_state SET rolled_up_to = $1")
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(total)
+}
+
+// Largest number of counters taken from Redis per flush round trip
+const COUNTER_FLUSH_BATCH: usize = 1000;
+
+// Move the live Redis click counters into `link_click_counters` every `interval`,
+// with a last flush on shutdown
+pub async fn run_counter_flush(
+    redis: RedisManager,
+    pool: PgPool,
+    interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        let stopping = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = shutdown.wait() => true,
+        };
+
+        if let Err(e) = flush_click_counters(&redis, &pool).await {
+            tracing::error!("Click counter flush failed: {}", e);
+        }
+        if stopping {
+            break;
+        }
+    }
+}
+
+pub async fn flush_click_counters(
+    redis: &RedisManager,
+    pool: &PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let counts = redis.take_click_counts(COUNTER_FLUSH_BATCH).await?;
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let short_codes: Vec<&str> = counts.iter().map(|(code, _, _)| code.as_str()).collect();
+        let days: Vec<NaiveDate> = counts.iter().map(|(_, day, _)| *day).collect();
+        let clicks: Vec<i64> = counts.iter().map(|(_, _, clicks)| *clicks).collect();
+
+        let result = sqlx::query(
+            "INSERT INTO link_click_counters (short_code, day, clicks)
+             SELECT * FROM UNNEST($1::text[], $2::date[], $3::bigint[])
+             ON CONFLICT (short_code, day) DO UPDATE
+             SET clicks = link_click_counters.clicks + EXCLUDED.clicks",
+        )
+        .bind(&short_codes)
+        .bind(&days)
+        .bind(&clicks)
+        .execute(pool)
+        .await;
+
+        if let Err(e) = result {
+            // Put the counts back so the next flush retries them
+            redis.add_click_counts(&counts).await?;
+            return Err(e.into());
+        }
+        if counts.len() < COUNTER_FLUSH_BATCH {
+            return Ok(());
+        }
+    }
+}
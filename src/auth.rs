@@ -0,0 +1,82 @@
+use axum::{extract::FromRequestParts, http::request::Parts};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::errors::AppError;
+use crate::AppState;
+
+// Prefix on every generated key so leaked keys are easy to recognise
+const KEY_PREFIX: &str = "cc_";
+
+// The API key a request was authenticated with
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub tier: String,
+    // Per-key override of the tier's daily link quota
+    pub daily_quota: Option<i64>,
+    // May call the /api/admin endpoints
+    pub is_admin: bool,
+}
+
+impl ApiKey {
+    pub fn require_admin(&self) -> Result<(), AppError> {
+        if self.is_admin {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+// Keys are stored as a SHA-256 hex digest, never in plaintext
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+pub fn generate_key() -> String {
+    format!("{}{}", KEY_PREFIX, nanoid::nanoid!(32))
+}
+
+// Accepts `Authorization: Bearer <key>` or `X-API-Key: <key>`
+fn key_from_headers(parts: &Parts) -> Option<&str> {
+    if let Some(value) = parts.headers.get(axum::http::header::AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    parts.headers.get("x-api-key")?.to_str().ok()
+}
+
+impl FromRequestParts<Arc<AppState>> for ApiKey {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        // Already resolved by the rate-limit middleware
+        if let Some(api_key) = parts.extensions.get::<ApiKey>() {
+            return Ok(api_key.clone());
+        }
+
+        let key = key_from_headers(parts).ok_or(AppError::Unauthorized)?;
+
+        let (id, name, tier, daily_quota, is_admin): (i64, String, String, Option<i64>, bool) =
+            sqlx::query_as(
+                "SELECT id, name, tier, daily_quota, is_admin FROM api_keys
+             WHERE key_hash = $1 AND revoked_at IS NULL",
+            )
+            .bind(hash_key(key.trim()))
+            .fetch_optional(&state.db.writer)
+            .await?
+            .ok_or(AppError::Unauthorized)?;
+
+        Ok(ApiKey {
+            id,
+            name,
+            tier,
+            daily_quota,
+            is_admin,
+        })
+    }
+}
@@ -4,18 +4,38 @@ use bytes::Bytes;
 use chrono::{Datelike, NaiveDate, Utc};
 use qfilter::Filter;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
 use std::collections::HashMap;
-use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
-use crate::distributed_filter::{generate_partition_name, DistributedFilter, PartitionFilter};
+use crate::config::FilterConfig;
+use crate::distributed_filter::{
+    future_start, generate_partition_name, DistributedFilter, FilterError, PartitionError,
+    PartitionFilter,
+};
+use crate::shutdown::Shutdown;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("S3 error: {0}")]
+    S3(#[from] aws_sdk_s3::Error),
+    #[error("Failed to read snapshot body: {0}")]
+    Body(#[from] aws_sdk_s3::primitives::ByteStreamError),
+    #[error("Snapshot encoding error: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error("Snapshot object has no key")]
+    MissingKey,
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DistributedFilterSnapshot {
     partition_filters: HashMap<String, PartitionFilterData>,
     future_partition: Option<PartitionFilterData>,
+    // Change-feed position the snapshot was taken at; newer events are replayed on restore
+    last_event_id: Option<String>,
     metadata: SnapshotMetadata,
 }
 
@@ -34,6 +54,13 @@ struct SnapshotMetadata {
     partition_count: usize,
 }
 
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub key: String,
+    pub last_modified: Option<String>,
+    pub size: i64,
+}
+
 pub struct DistributedFilterPersistence {
     s3_client: Client,
     bucket: String,
@@ -41,7 +68,7 @@ pub struct DistributedFilterPersistence {
 }
 
 impl DistributedFilterPersistence {
-    pub async fn new(bucket: String, prefix: String) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(bucket: String, prefix: String) -> Result<Self, PersistenceError> {
         let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
         let s3_client = Client::new(&config);
 
@@ -55,7 +82,15 @@ impl DistributedFilterPersistence {
     pub async fn save_snapshot(
         &self,
         distributed_filter: &DistributedFilter,
-    ) -> Result<String, Box<dyn Error>> {
+    ) -> Result<String, PersistenceError> {
+        let serialized = Self::encode_snapshot(distributed_filter)?;
+        self.upload_snapshot(serialized).await
+    }
+
+    // Serialize the filter; only this part needs the filter lock
+    pub fn encode_snapshot(
+        distributed_filter: &DistributedFilter,
+    ) -> Result<Vec<u8>, bincode::Error> {
         let mut partition_filters = HashMap::new();
         let mut total_items = 0u64;
 
@@ -74,18 +109,19 @@ impl DistributedFilterPersistence {
         }
 
         // Handle future partition
-        let future_partition = distributed_filter.future_partition.as_ref().map(|f| {
-            let filter_bytes = bincode::serialize(&f.filter).unwrap();
-            PartitionFilterData {
-                filter_data: filter_bytes,
+        let future_partition = match &distributed_filter.future_partition {
+            Some(f) => Some(PartitionFilterData {
+                filter_data: bincode::serialize(&f.filter)?,
                 start_date: f.start_date,
                 end_date: f.end_date,
-            }
-        });
+            }),
+            None => None,
+        };
 
         let snapshot = DistributedFilterSnapshot {
             partition_filters,
             future_partition,
+            last_event_id: distributed_filter.last_event_id.clone(),
             metadata: SnapshotMetadata {
                 timestamp: Utc::now(),
                 total_items_count: total_items,
@@ -96,7 +132,10 @@ impl DistributedFilterPersistence {
         // Serialize the entire snapshot
         let serialized = bincode::serialize(&snapshot)?;
         tracing::info!("Serialized data size: {} bytes", serialized.len());
+        Ok(serialized)
+    }
 
+    pub async fn upload_snapshot(&self, serialized: Vec<u8>) -> Result<String, PersistenceError> {
         let key = format!(
             "{}/distributed_filter_{}.bin",
             self.prefix,
@@ -109,19 +148,24 @@ impl DistributedFilterPersistence {
             .key(&key)
             .body(Bytes::from(serialized).into())
             .send()
-            .await?;
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
 
         Ok(key)
     }
 
-    pub async fn load_latest_snapshot(&self) -> Result<Option<DistributedFilter>, Box<dyn Error>> {
+    pub async fn load_latest_snapshot(
+        &self,
+        filter_config: &FilterConfig,
+    ) -> Result<Option<DistributedFilter>, PersistenceError> {
         let objects = self
             .s3_client
             .list_objects_v2()
             .bucket(&self.bucket)
             .prefix(&self.prefix)
             .send()
-            .await?;
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
 
         let slice_of_objects = objects.contents();
 
@@ -139,9 +183,10 @@ impl DistributedFilterPersistence {
             .s3_client
             .get_object()
             .bucket(&self.bucket)
-            .key(latest.key().unwrap())
+            .key(latest.key().ok_or(PersistenceError::MissingKey)?)
             .send()
-            .await?;
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
 
         let data = response.body.collect().await?.into_bytes();
 
@@ -149,7 +194,7 @@ impl DistributedFilterPersistence {
         let snapshot: DistributedFilterSnapshot = bincode::deserialize(&data)?;
 
         // Reconstruct DistributedFilter
-        let mut distributed_filter = DistributedFilter::new()?;
+        let mut distributed_filter = DistributedFilter::new(filter_config.clone());
 
         // Restore partition filters
         for (name, filter_data) in snapshot.partition_filters {
@@ -176,6 +221,8 @@ impl DistributedFilterPersistence {
             });
         }
 
+        distributed_filter.last_event_id = snapshot.last_event_id;
+
         tracing::info!(
             "Restored distributed filter with {} partitions and {} total items",
             snapshot.metadata.partition_count,
@@ -185,6 +232,32 @@ impl DistributedFilterPersistence {
         Ok(Some(distributed_filter))
     }
 
+    pub async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, PersistenceError> {
+        let objects = self
+            .s3_client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+
+        let mut snapshots: Vec<SnapshotInfo> = objects
+            .contents()
+            .iter()
+            .filter_map(|obj| {
+                Some(SnapshotInfo {
+                    key: obj.key()?.to_string(),
+                    last_modified: obj.last_modified().map(|lm| lm.to_string()),
+                    size: obj.size().unwrap_or_default(),
+                })
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(snapshots)
+    }
+
     // pub async fn cleanup_old_snapshots(&self, keep_last_n: i32) -> Result<(), Box<dyn Error>> {
     //     let objects = self
     //         .s3_client
@@ -219,50 +292,38 @@ impl DistributedFilterPersistence {
     // }
 }
 
-// Update initialize_distributed_filter_system to use persistence
-pub async fn initialize_distributed_filter_system(
-    _pool: &PgPool,
-    bucket: String,
-    prefix: String,
-) -> Result<Arc<Mutex<DistributedFilter>>, Box<dyn Error>> {
-    let _persistence = DistributedFilterPersistence::new(bucket, prefix).await?;
+// Build an empty filter with one partition per month in the window, plus the
+// future partition
+pub fn initialize_distributed_filter_system(
+    filter_config: &FilterConfig,
+) -> Result<Arc<Mutex<DistributedFilter>>, FilterError> {
+    let mut distributed_filter = DistributedFilter::new(filter_config.clone());
 
-    // Try to load the latest snapshot
-    // if let Some(distributed_filter) = persistence.load_latest_snapshot().await? {
-    //     tracing::info!("Successfully restored distributed filter from snapshot");
-    //     return Ok(Arc::new(Mutex::new(distributed_filter)));
-    // }
-
-    // If no snapshot exists, create a new distributed filter
-    tracing::info!("No snapshot found, creating new distributed filter");
-    let mut distributed_filter = DistributedFilter::new()?;
-
-    // Initialize partitions for the next 36 months
+    // Initialize partitions for the configured window
     let current_date = Utc::now().naive_utc().date();
 
-    for months_ahead in 0..36 {
+    let out_of_range = || FilterError::from(PartitionError::OutOfRange(current_date));
+    for months_ahead in 0..filter_config.window_months {
         let start_date = current_date
             .checked_add_months(chrono::Months::new(months_ahead))
-            .unwrap()
-            .with_day(1)
-            .unwrap();
+            .and_then(|date| date.with_day(1))
+            .ok_or_else(out_of_range)?;
 
         let end_date = start_date
             .checked_add_months(chrono::Months::new(1))
-            .unwrap();
+            .ok_or_else(out_of_range)?;
 
         let partition_name = generate_partition_name(start_date);
         distributed_filter.create_partition_filter(partition_name, start_date, end_date)?;
     }
 
-    // Initialize future partition
-    let future_start = current_date
-        .checked_add_months(chrono::Months::new(36))
-        .unwrap();
+    // Initialize future partition, starting where the monthly filters end
+    let future_start =
+        future_start(current_date, filter_config.window_months).ok_or_else(out_of_range)?;
     let future_end = NaiveDate::MAX;
 
     distributed_filter.future_partition = Some(PartitionFilter {
-        filter: Filter::new(1_000_000, 0.01)?,
+        filter: distributed_filter.new_filter()?,
         start_date: future_start,
         end_date: future_end,
     });
@@ -270,21 +331,33 @@ pub async fn initialize_distributed_filter_system(
     Ok(Arc::new(Mutex::new(distributed_filter)))
 }
 
-// Add snapshot service for distributed filter
+// Save a snapshot every `interval`, so a crash loses at most that much. The
+// upload runs without the filter lock.
 pub async fn run_distributed_snapshot_service(
     distributed_filter: Arc<Mutex<DistributedFilter>>,
     persistence: Arc<DistributedFilterPersistence>,
+    interval: Duration,
+    mut shutdown: Shutdown,
 ) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(12000)); // Save every hour
+    // The filter was just loaded, so the first snapshot waits a full interval
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
 
     loop {
-        interval.tick().await;
-        match persistence
-            .save_snapshot(&*distributed_filter.lock().await)
-            .await
-        {
-            Ok(key) => tracing::info!("Successfully saved distributed filter snapshot: {}", key),
-            Err(e) => tracing::error!("Failed to save distributed filter snapshot: {}", e),
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+
+        let encoded =
+            DistributedFilterPersistence::encode_snapshot(&*distributed_filter.lock().await);
+        match encoded {
+            Ok(serialized) => match persistence.upload_snapshot(serialized).await {
+                Ok(key) => {
+                    tracing::info!("Successfully saved distributed filter snapshot: {}", key)
+                }
+                Err(e) => tracing::error!("Failed to save distributed filter snapshot: {}", e),
+            },
+            Err(e) => tracing::error!("Failed to encode distributed filter snapshot: {}", e),
         }
 
         // // Cleanup old snapshots, keeping last 24
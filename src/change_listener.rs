@@ -0,0 +1,109 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::distributed_filter::DistributedFilter;
+use crate::metrics::{URL_CHANGES_APPLIED, URL_CHANGE_LISTENER_RECONNECTS};
+use crate::redis::RedisManager;
+use crate::shutdown::Shutdown;
+
+// Channel the `urls_changed` trigger notifies on
+const CHANNEL: &str = "urls_changed";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Payload of one notification; an UPDATE carries both expiry dates
+#[derive(Deserialize, Debug)]
+struct UrlChange {
+    op: String,
+    short_code: String,
+    old_expiry_date: Option<NaiveDate>,
+    new_expiry_date: Option<NaiveDate>,
+}
+
+// Evict cached links and fix the filter when `urls` is changed outside CargoCut.
+// Every instance listens, since each keeps its own filter.
+pub async fn run_change_listener(
+    pool: PgPool,
+    filter: Arc<Mutex<DistributedFilter>>,
+    redis: RedisManager,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        match PgListener::connect_with(&pool).await {
+            Ok(mut listener) => match listen(&mut listener, &filter, &redis, &mut shutdown).await {
+                Ok(()) => return,
+                Err(e) => error!("urls change listener failed, reconnecting: {}", e),
+            },
+            Err(e) => error!("urls change listener could not connect: {}", e),
+        }
+        URL_CHANGE_LISTENER_RECONNECTS.inc();
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.wait() => return,
+        }
+    }
+}
+
+// Returns once shutdown starts
+async fn listen(
+    listener: &mut PgListener,
+    filter: &Mutex<DistributedFilter>,
+    redis: &RedisManager,
+    shutdown: &mut Shutdown,
+) -> Result<(), sqlx::Error> {
+    listener.listen(CHANNEL).await?;
+    info!("Listening for urls changes on {}", CHANNEL);
+
+    loop {
+        let notification = tokio::select! {
+            notification = listener.try_recv() => notification?,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        let Some(notification) = notification else {
+            // sqlx reconnected on its own; anything sent meanwhile was lost
+            warn!("urls change listener lost its connection, changes may have been missed");
+            URL_CHANGE_LISTENER_RECONNECTS.inc();
+            continue;
+        };
+
+        match serde_json::from_str::<UrlChange>(notification.payload()) {
+            Ok(change) => apply_change(&change, filter, redis).await,
+            Err(e) => warn!(
+                "Skipping malformed urls change {:?}: {}",
+                notification.payload(),
+                e
+            ),
+        }
+    }
+}
+
+async fn apply_change(change: &UrlChange, filter: &Mutex<DistributedFilter>, redis: &RedisManager) {
+    if let Err(e) = redis.delete_short_url(&change.short_code).await {
+        error!(
+            "Failed to evict {} after an external {}: {}",
+            change.short_code, change.op, e
+        );
+    }
+
+    if change.old_expiry_date != change.new_expiry_date {
+        let mut filter = filter.lock().await;
+        if let Some(old) = change.old_expiry_date {
+            filter.remove(&change.short_code, old);
+        }
+        if let Some(new) = change.new_expiry_date {
+            if let Err(e) = filter.insert(&change.short_code, new) {
+                error!(
+                    "Failed to add {} to the filter after an external {}: {}",
+                    change.short_code, change.op, e
+                );
+            }
+        }
+    }
+    URL_CHANGES_APPLIED.inc();
+}
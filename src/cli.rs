@@ -0,0 +1,116 @@
+use clap::{Args, Parser, Subcommand};
+
+use crate::models::ExpiresAt;
+
+#[derive(Parser, Debug)]
+#[command(name = "cargocut", version, about = "CargoCut URL shortener")]
+pub struct Cli {
+    /// Path to the TOML config file (defaults to ./cargocut.toml or CARGOCUT_CONFIG)
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// Print the effective configuration and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run migrations and start the HTTP server (default)
+    Serve,
+    /// Run database migrations and exit
+    Migrate,
+    /// Manage distributed filter snapshots in S3
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Inspect and maintain the monthly `urls` partitions
+    Partitions {
+        #[command(subcommand)]
+        action: PartitionAction,
+    },
+    /// Maintain the distributed filter
+    Filter {
+        #[command(subcommand)]
+        action: FilterAction,
+    },
+    /// Create a short link directly
+    Create(CreateArgs),
+    /// Manage API keys
+    Keys {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyAction {
+    /// Create a key and print it; the plaintext is not stored
+    Create {
+        name: String,
+        /// Rate limit tier from `rate_limit.tiers`
+        #[arg(long, default_value = "default")]
+        tier: String,
+        /// Links per day, overriding the tier's quota
+        #[arg(long)]
+        daily_quota: Option<i64>,
+        /// Allow the key to call the /api/admin endpoints
+        #[arg(long)]
+        admin: bool,
+    },
+    /// List keys
+    List,
+    /// Revoke a key by id
+    Revoke { id: i64 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotAction {
+    /// Rebuild the filter from the database and upload a snapshot
+    Save,
+    /// Download the latest snapshot and print a summary
+    Load,
+    /// List stored snapshots
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PartitionAction {
+    /// List partitions of the `urls` table and their ranges
+    List,
+    /// Create the partition for a month, e.g. `2025-07`
+    Create { month: String },
+    /// Drop every monthly partition that ends before the current month
+    Prune,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FilterAction {
+    /// Rebuild the filter from live rows and report per-partition counts
+    Rebuild {
+        /// Upload the rebuilt filter as a new snapshot
+        #[arg(long)]
+        save: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct CreateArgs {
+    pub long_url: String,
+    #[arg(long, conflicts_with = "expires_at")]
+    pub months_valid: Option<u32>,
+    /// RFC 3339 timestamp, or "never"
+    #[arg(long)]
+    pub expires_at: Option<ExpiresAt>,
+    #[arg(long)]
+    pub custom_short_code: Option<String>,
+    #[arg(long)]
+    pub redirect_type: Option<u16>,
+    /// Record the link as owned by this API key id
+    #[arg(long)]
+    pub owner_key_id: Option<i64>,
+}
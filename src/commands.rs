@@ -0,0 +1,304 @@
+use chrono::NaiveDate;
+use sqlx::{migrate::Migrator, PgPool};
+use std::error::Error;
+use std::sync::{atomic::AtomicBool, Arc};
+
+use crate::analytics::ClickRecorder;
+use crate::auth::{generate_key, hash_key};
+use crate::aws::persistance::{initialize_distributed_filter_system, DistributedFilterPersistence};
+use crate::cli::{CreateArgs, FilterAction, KeyAction, PartitionAction, SnapshotAction};
+use crate::config::Config;
+use crate::db::{self, Database};
+use crate::distributed_filter::{create_new_partition, generate_partition_name, DistributedFilter};
+use crate::expiry::ExpiryPolicy;
+use crate::filter_audit::AuditSettings;
+use crate::filter_sync::{FilterSync, FEED_START};
+use crate::models::CreateUrl;
+use crate::rate_limit::{ApiRateLimiter, TrustedProxies};
+use crate::redis::RedisManager;
+use crate::{create_link, cron, AppState};
+
+pub async fn run_migrations(pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let migrator = Migrator::new(std::path::Path::new("./migrations")).await?;
+    migrator.run(pool).await?;
+    Ok(())
+}
+
+pub async fn migrate(config: &Config) -> Result<(), Box<dyn Error>> {
+    let pool = db::connect_pool(&config.database.url).await?;
+    run_migrations(&pool).await?;
+    println!("Migrations applied");
+    Ok(())
+}
+
+pub async fn snapshot(config: &Config, action: SnapshotAction) -> Result<(), Box<dyn Error>> {
+    let persistence = DistributedFilterPersistence::new(
+        config.snapshot.bucket.clone(),
+        config.snapshot.prefix.clone(),
+    )
+    .await?;
+
+    match action {
+        SnapshotAction::Save => {
+            let pool = db::connect_pool(&config.database.url).await?;
+            let filter = rebuild_filter(config, &pool).await?;
+            let key = persistence.save_snapshot(&filter).await?;
+            println!("Saved snapshot {}", key);
+        }
+        SnapshotAction::Load => match persistence.load_latest_snapshot(&config.filter).await? {
+            Some(filter) => print_filter_summary(&filter),
+            None => println!("No snapshot found"),
+        },
+        SnapshotAction::List => {
+            for snapshot in persistence.list_snapshots().await? {
+                println!(
+                    "{}\t{}\t{} bytes",
+                    snapshot.key,
+                    snapshot.last_modified.unwrap_or_default(),
+                    snapshot.size
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn partitions(config: &Config, action: PartitionAction) -> Result<(), Box<dyn Error>> {
+    let pool = db::connect_pool(&config.database.url).await?;
+
+    match action {
+        PartitionAction::List => {
+            let rows: Vec<(String, String)> = sqlx::query_as(
+                "SELECT inhrelid::regclass::text, pg_get_expr(c.relpartbound, c.oid)
+                 FROM pg_inherits
+                 JOIN pg_class c ON inhrelid = c.oid
+                 WHERE inhparent = 'urls'::regclass
+                 ORDER BY 1",
+            )
+            .fetch_all(&pool)
+            .await?;
+
+            for (name, range) in rows {
+                println!("{}\t{}", name, range);
+            }
+        }
+        PartitionAction::Create { month } => {
+            let start_date = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+                .map_err(|_| format!("invalid month {:?}, expected YYYY-MM", month))?;
+            let end_date = start_date
+                .checked_add_months(chrono::Months::new(1))
+                .ok_or("month out of range")?;
+            let partition_name = generate_partition_name(start_date);
+
+            create_new_partition(&pool, &partition_name, start_date, end_date).await?;
+            println!("Created {} [{}, {})", partition_name, start_date, end_date);
+        }
+        PartitionAction::Prune => {
+            let dropped =
+                cron::cleanup_expired_partitions(&pool, chrono::Utc::now().date_naive()).await?;
+            if dropped.is_empty() {
+                println!("No expired partitions");
+            }
+            for partition_name in dropped {
+                println!("Dropped {}", partition_name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn filter(config: &Config, action: FilterAction) -> Result<(), Box<dyn Error>> {
+    match action {
+        FilterAction::Rebuild { save } => {
+            let pool = db::connect_pool(&config.database.url).await?;
+            let filter = rebuild_filter(config, &pool).await?;
+            print_filter_summary(&filter);
+
+            if save {
+                let persistence = DistributedFilterPersistence::new(
+                    config.snapshot.bucket.clone(),
+                    config.snapshot.prefix.clone(),
+                )
+                .await?;
+                let key = persistence.save_snapshot(&filter).await?;
+                println!("Saved snapshot {}", key);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn create(config: &Config, args: CreateArgs) -> Result<(), Box<dyn Error>> {
+    let db = Database::connect(&config.database).await?;
+    let distributed_filter = initialize_distributed_filter_system(&config.filter)?;
+    let redis = RedisManager::new(&config.redis).await?;
+
+    let state = AppState {
+        db,
+        distributed_filter,
+        redis: redis.clone(),
+        default_redirect: config.default_redirect(),
+        max_batch_size: config.links.max_batch_size,
+        expiry_policy: ExpiryPolicy::new(&config.links),
+        window_months: config.filter.window_months,
+        api_limiter: Arc::new(ApiRateLimiter::new(&config.rate_limit.tiers, None)),
+        trusted_proxies: Arc::new(TrustedProxies::default()),
+        clicks: ClickRecorder::disabled(),
+        filter_sync: if config.filter.sync {
+            FilterSync::new(redis.clone(), config.filter.sync_max_events)
+        } else {
+            FilterSync::disabled()
+        },
+        filter_trust: config.filter.trust,
+        audit: AuditSettings::new(&config.filter),
+        filter_ready: Arc::new(AtomicBool::new(true)),
+    };
+    let payload = CreateUrl {
+        long_url: args.long_url,
+        months_valid: args.months_valid,
+        expires_at: args.expires_at,
+        expires_in: None,
+        custom_short_code: args.custom_short_code,
+        redirect_type: args.redirect_type,
+    };
+
+    let response = create_link(&state, payload, args.owner_key_id).await?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct KeyRow {
+    id: i64,
+    name: String,
+    tier: String,
+    daily_quota: Option<i64>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    is_admin: bool,
+}
+
+pub async fn keys(config: &Config, action: KeyAction) -> Result<(), Box<dyn Error>> {
+    let pool = db::connect_pool(&config.database.url).await?;
+
+    match action {
+        KeyAction::Create {
+            name,
+            tier,
+            daily_quota,
+            admin,
+        } => {
+            if !config.rate_limit.tiers.contains_key(&tier) {
+                return Err(format!("unknown rate limit tier {:?}", tier).into());
+            }
+            let key = generate_key();
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO api_keys (name, key_hash, tier, daily_quota, is_admin)
+                 VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            )
+            .bind(&name)
+            .bind(hash_key(&key))
+            .bind(&tier)
+            .bind(daily_quota)
+            .bind(admin)
+            .fetch_one(&pool)
+            .await?;
+            let role = if admin { ", admin" } else { "" };
+            println!("Created key {} ({}, tier {}{})", id, name, tier, role);
+            println!("{}", key);
+            println!("Store this key now; it cannot be shown again.");
+        }
+        KeyAction::List => {
+            let rows: Vec<KeyRow> = sqlx::query_as(
+                "SELECT id, name, tier, daily_quota, created_at, revoked_at, is_admin
+                 FROM api_keys ORDER BY id",
+            )
+            .fetch_all(&pool)
+            .await?;
+            for row in rows {
+                let status = match row.revoked_at {
+                    Some(at) => format!("revoked {}", at),
+                    None => "active".to_string(),
+                };
+                let quota = match row.daily_quota {
+                    Some(quota) => quota.to_string(),
+                    None => "-".to_string(),
+                };
+                let role = if row.is_admin { "admin" } else { "-" };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    row.id, row.name, row.tier, quota, role, row.created_at, status
+                );
+            }
+        }
+        KeyAction::Revoke { id } => {
+            let result = sqlx::query(
+                "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            )
+            .bind(id)
+            .execute(&pool)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(format!("no active key with id {}", id).into());
+            }
+            println!("Revoked key {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+// Build an empty filter window and fill it from the live rows in `urls`
+async fn rebuild_filter(
+    config: &Config,
+    pool: &PgPool,
+) -> Result<DistributedFilter, Box<dyn Error>> {
+    let distributed_filter = initialize_distributed_filter_system(&config.filter)?;
+
+    let mut filter = Arc::try_unwrap(distributed_filter)
+        .map_err(|_| "filter is still shared")?
+        .into_inner();
+
+    // Record the change-feed position first so a server restoring this snapshot
+    // replays the changes made during the rebuild
+    if config.filter.sync {
+        let redis = RedisManager::new(&config.redis).await?;
+        filter.last_event_id = Some(
+            redis
+                .filter_event_bound(true)
+                .await?
+                .unwrap_or_else(|| FEED_START.to_string()),
+        );
+    }
+    let inserted = filter.rebuild_from_database(pool).await?;
+    println!("Inserted {} short codes", inserted);
+
+    Ok(filter)
+}
+
+fn print_filter_summary(filter: &DistributedFilter) {
+    let mut names: Vec<&String> = filter.filters.keys().collect();
+    names.sort();
+
+    for name in names {
+        let partition = &filter.filters[name];
+        println!(
+            "{}\t{} .. {}\t{} items",
+            name,
+            partition.start_date,
+            partition.end_date,
+            partition.filter.len()
+        );
+    }
+    if let Some(future) = &filter.future_partition {
+        println!(
+            "future\t{} .. {}\t{} items",
+            future.start_date,
+            future.end_date,
+            future.filter.len()
+        );
+    }
+}
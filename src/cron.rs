@@ -1,19 +1,191 @@
-// Function to drop expired partitions
-pub async fn cleanup_expired_partitions(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
-    let expired_month = chrono::Utc::now()
-        .date_naive()
-        .checked_sub_months(chrono::Months::new(1))
-        .unwrap();
-
-    let partition_name = format!(
-        "urls_y{}m{}",
-        expired_month.format("%Y"),
-        expired_month.format("%m")
-    );
-
-    sqlx::query(&format!("DROP TABLE IF EXISTS {}", partition_name))
-        .execute(pool)
+use chrono::{NaiveDate, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::distributed_filter::{
+    create_new_partition, future_start, generate_partition_name, month_bounds, DistributedFilter,
+    FilterError, PartitionError, WindowState,
+};
+use crate::metrics::{
+    FILTER_FUTURE_ITEMS, FILTER_FUTURE_START, FILTER_WINDOW_PARTITIONS,
+    PARTITION_MAINTENANCE_LAST_SUCCESS,
+};
+use crate::shutdown::Shutdown;
+
+// Drop every monthly `urls` partition whose upper bound is at or before the
+// start of the current month; every row in it has expired. Returns the dropped names.
+pub async fn cleanup_expired_partitions(
+    pool: &PgPool,
+    current_date: NaiveDate,
+) -> Result<Vec<String>, sqlx::Error> {
+    let (month_start, _) = month_bounds(current_date)
+        .ok_or_else(|| sqlx::Error::Protocol("current date out of range".into()))?;
+
+    let expired: Vec<String> = sqlx::query_scalar(
+        "SELECT relname FROM (
+             SELECT c.relname::text AS relname,
+                    substring(pg_get_expr(c.relpartbound, c.oid) FROM 'TO \\(''([^'']+)''\\)')::date AS upper_bound
+             FROM pg_inherits i
+             JOIN pg_class c ON c.oid = i.inhrelid
+             WHERE i.inhparent = 'urls'::regclass AND c.relname LIKE 'urls\\_y%'
+         ) p
+         WHERE upper_bound <= $1
+         ORDER BY relname",
+    )
+    .bind(month_start)
+    .fetch_all(pool)
+    .await?;
+
+    for partition_name in &expired {
+        // Names come from the catalog, so they only need quoting
+        sqlx::query(&format!("DROP TABLE IF EXISTS \"{}\"", partition_name))
+            .execute(pool)
+            .await?;
+        tracing::info!("Dropped expired partition {}", partition_name);
+    }
+    Ok(expired)
+}
+
+// Keep `window_months` of monthly partitions and filters ahead of today and hand
+// months that have entered the window over from the future partition. Database
+// work runs without the filter lock so redirects aren't held up.
+pub async fn maintain_partition_window(
+    filter: &Mutex<DistributedFilter>,
+    pool: &PgPool,
+    current_date: NaiveDate,
+) -> Result<WindowState, FilterError> {
+    let window_months = filter.lock().await.config.window_months;
+    let window_end = future_start(current_date, window_months)
+        .ok_or(PartitionError::OutOfRange(current_date))?;
+
+    cleanup_expired_partitions(pool, current_date).await?;
+
+    // Also moves any rows for these months out of urls_future
+    let (mut start_date, mut end_date) =
+        month_bounds(current_date).ok_or(PartitionError::OutOfRange(current_date))?;
+    while start_date < window_end {
+        create_new_partition(
+            pool,
+            &generate_partition_name(start_date),
+            start_date,
+            end_date,
+        )
+        .await?;
+        (start_date, end_date) =
+            month_bounds(end_date).ok_or(PartitionError::OutOfRange(end_date))?;
+    }
+
+    let mut rolled_over = false;
+    loop {
+        let Some(start_date) = filter.lock().await.next_rollover(window_end) else {
+            break;
+        };
+        rolled_over = true;
+        let (start_date, end_date) =
+            month_bounds(start_date).ok_or(PartitionError::OutOfRange(start_date))?;
+        let short_codes: Vec<String> = sqlx::query_scalar(
+            "SELECT short_code FROM urls
+             WHERE expiry_date >= $1 AND expiry_date < $2",
+        )
+        .bind(start_date)
+        .bind(end_date)
+        .fetch_all(pool)
         .await?;
 
+        filter
+            .lock()
+            .await
+            .move_from_future(start_date, end_date, &short_codes)?;
+    }
+
+    if rolled_over {
+        rebuild_future_filter(filter, pool).await?;
+    }
+
+    let mut filter = filter.lock().await;
+    filter.ensure_window(current_date, window_end)?;
+    Ok(filter.window_state())
+}
+
+// Replace the future filter with one holding only rows still beyond the window,
+// so codes copied out at rollover don't pile up in it
+async fn rebuild_future_filter(
+    filter: &Mutex<DistributedFilter>,
+    pool: &PgPool,
+) -> Result<(), FilterError> {
+    let (start_date, mut rebuilt) = {
+        let mut filter = filter.lock().await;
+        let Some(start_date) = filter.begin_future_rebuild() else {
+            return Ok(());
+        };
+        (start_date, filter.new_filter()?)
+    };
+
+    let result: Result<usize, FilterError> = async {
+        let short_codes: Vec<String> =
+            sqlx::query_scalar("SELECT short_code FROM urls WHERE expiry_date >= $1")
+                .bind(start_date)
+                .fetch_all(pool)
+                .await?;
+        for short_code in &short_codes {
+            rebuilt.insert_duplicated(short_code)?;
+        }
+        Ok(short_codes.len())
+    }
+    .await;
+
+    let mut filter = filter.lock().await;
+    let count = match result {
+        Ok(count) => count,
+        Err(e) => {
+            filter.abort_future_rebuild();
+            return Err(e);
+        }
+    };
+    if filter.finish_future_rebuild(start_date, rebuilt)? {
+        tracing::info!(
+            "Rebuilt the future filter from {} with {} short codes",
+            start_date,
+            count
+        );
+    }
     Ok(())
 }
+
+pub async fn run_partition_maintenance(
+    filter: Arc<Mutex<DistributedFilter>>,
+    pool: PgPool,
+    interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+
+        match maintain_partition_window(&filter, &pool, Utc::now().date_naive()).await {
+            Ok(state) => {
+                FILTER_WINDOW_PARTITIONS.set(state.monthly_partitions as f64);
+                FILTER_FUTURE_ITEMS.set(state.future_items as f64);
+                if let Some(future_start) = state.future_start.and_then(|d| d.and_hms_opt(0, 0, 0))
+                {
+                    FILTER_FUTURE_START.set(future_start.and_utc().timestamp() as f64);
+                }
+                PARTITION_MAINTENANCE_LAST_SUCCESS.set(Utc::now().timestamp() as f64);
+                tracing::info!(
+                    "Partition window: {} monthly filters ({:?} to {:?}), future partition from {:?} with {} codes",
+                    state.monthly_partitions,
+                    state.first_month,
+                    state.last_month,
+                    state.future_start,
+                    state.future_items
+                );
+            }
+            Err(e) => tracing::error!("Partition maintenance failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use crate::config::DatabaseConfig;
+use crate::metrics::REPLICA_AVAILABLE;
+use crate::shutdown::Shutdown;
+
+// Set on every CargoCut session; the `urls` change trigger stays quiet for these
+pub const APPLICATION_NAME: &str = "cargocut";
+// A lookup waits this long for a replica connection before failing over to the
+// writer, instead of sqlx's 30 second default
+const REPLICA_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn connect_options(url: &str) -> Result<PgConnectOptions, sqlx::Error> {
+    Ok(PgConnectOptions::from_str(url)?.application_name(APPLICATION_NAME))
+}
+
+pub async fn connect_pool(url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPool::connect_with(connect_options(url)?).await
+}
+
+pub struct LiveUrl {
+    pub long_url: String,
+    pub redirect_type: i16,
+    pub expiry_date: chrono::NaiveDate,
+}
+
+// Writer pool for inserts plus an optional read-only replica for lookups
+#[derive(Clone)]
+pub struct Database {
+    pub writer: PgPool,
+    replica: Option<PgPool>,
+    replica_healthy: Arc<AtomicBool>,
+}
+
+impl Database {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let writer = connect_pool(&config.url).await?;
+
+        let replica = if config.read_url.is_empty() {
+            None
+        } else {
+            // Lazy so a replica that is down at startup doesn't stop the service
+            Some(
+                PgPoolOptions::new()
+                    .acquire_timeout(REPLICA_ACQUIRE_TIMEOUT)
+                    .connect_lazy_with(connect_options(&config.read_url)?),
+            )
+        };
+
+        Ok(Database {
+            writer,
+            replica,
+            // Reads stay on the writer until the first lag check passes
+            replica_healthy: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Pool for read-only queries: the replica while it is healthy, else the writer
+    pub fn reader(&self) -> &PgPool {
+        match &self.replica {
+            Some(replica) if self.replica_healthy.load(Ordering::Relaxed) => replica,
+            _ => &self.writer,
+        }
+    }
+
+    // Look up a live link on the reader, retrying on the writer if the replica errors
+    pub async fn find_live_url(&self, short_code: &str) -> Result<Option<LiveUrl>, sqlx::Error> {
+        let reader = self.reader();
+        match fetch_live_url(reader, short_code).await {
+            Err(e) if !std::ptr::eq(reader, &self.writer) => {
+                tracing::warn!("Replica lookup failed, retrying on writer: {}", e);
+                fetch_live_url(&self.writer, short_code).await
+            }
+            result => result,
+        }
+    }
+
+    // Periodically check replica reachability and lag, flipping reads to the writer when needed
+    pub async fn monitor_replica(
+        self,
+        max_lag: Duration,
+        check_interval: Duration,
+        mut shutdown: Shutdown,
+    ) {
+        let Some(replica) = self.replica.clone() else {
+            return;
+        };
+        let mut interval = tokio::time::interval(check_interval);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait() => break,
+            }
+
+            // Lag is zero when everything received has been replayed, otherwise the
+            // age of the last replayed transaction
+            let lag: Result<f64, sqlx::Error> = sqlx::query_scalar(
+                "SELECT CASE
+                    WHEN NOT pg_is_in_recovery() THEN 0
+                    WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+                    ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0)
+                 END::float8",
+            )
+            .fetch_one(&replica)
+            .await;
+
+            let healthy = match lag {
+                Ok(lag) if lag <= max_lag.as_secs_f64() => true,
+                Ok(lag) => {
+                    tracing::warn!("Read replica lagging by {:.1}s, reading from writer", lag);
+                    false
+                }
+                Err(e) => {
+                    tracing::warn!("Read replica unavailable, reading from writer: {}", e);
+                    false
+                }
+            };
+
+            self.replica_healthy.store(healthy, Ordering::Relaxed);
+            REPLICA_AVAILABLE.set(if healthy { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+async fn fetch_live_url(pool: &PgPool, short_code: &str) -> Result<Option<LiveUrl>, sqlx::Error> {
+    sqlx::query_as!(
+        LiveUrl,
+        "SELECT long_url, redirect_type, expiry_date AS \"expiry_date: chrono::NaiveDate\" FROM urls
+         WHERE short_code = $1
+         AND expiry_date >= CURRENT_DATE
+         AND (expires_at IS NULL OR expires_at > now())",
+        short_code
+    )
+    .fetch_optional(pool)
+    .await
+}
@@ -1,9 +1,33 @@
-use chrono::{Datelike, NaiveDate, Utc};
+use chrono::{Datelike, Months, NaiveDate, Utc};
 use qfilter::Filter;
 use sqlx::PgPool;
 use std::collections::HashMap;
 use tracing::info;
 
+use crate::config::FilterConfig;
+
+// DEFAULT partition of `urls`: rows expiring after the monthly window, until
+// the window reaches their month
+pub const FUTURE_PARTITION: &str = "urls_future";
+
+#[derive(thiserror::Error, Debug)]
+pub enum PartitionError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("No partition can hold {0}: date out of range")]
+    OutOfRange(NaiveDate),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FilterError {
+    #[error("Filter error: {0}")]
+    Filter(#[from] qfilter::Error),
+    #[error(transparent)]
+    Partition(#[from] PartitionError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
 // Structure to hold filter information for a partition
 #[derive(Debug)]
 pub struct PartitionFilter {
@@ -17,14 +41,30 @@ pub struct PartitionFilter {
 pub struct DistributedFilter {
     pub filters: HashMap<String, PartitionFilter>,
     pub future_partition: Option<PartitionFilter>,
+    pub config: FilterConfig,
+    // Id of the last change-feed event reflected in the filter
+    pub last_event_id: Option<String>,
+    // Codes added to the future filter while a replacement is being built
+    future_rebuild: Option<Vec<String>>,
 }
 
 impl DistributedFilter {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(DistributedFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        DistributedFilter {
             filters: HashMap::new(),
             future_partition: None,
-        })
+            config,
+            last_event_id: None,
+            future_rebuild: None,
+        }
+    }
+
+    // Build an empty filter sized for one partition
+    pub fn new_filter(&self) -> Result<Filter, FilterError> {
+        Ok(Filter::new(
+            self.config.capacity,
+            self.config.false_positive_rate,
+        )?)
     }
 
     // Initialize a new partition filter
@@ -33,8 +73,8 @@ impl DistributedFilter {
         partition_name: String,
         start_date: NaiveDate,
         end_date: NaiveDate,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let filter = Filter::new(1_000_000, 0.01)?; // Adjust size based on expected partition size
+    ) -> Result<(), FilterError> {
+        let filter = self.new_filter()?;
         let partition_filter = PartitionFilter {
             filter,
             start_date,
@@ -45,97 +85,218 @@ impl DistributedFilter {
         Ok(())
     }
 
-    // Check if a short code exists in any relevant filter
+    // Check if a short code exists in any relevant filter. Months that ended
+    // before `current_date` only hold expired links and are skipped.
     pub fn contains(&self, short_code: &str, current_date: NaiveDate) -> bool {
-        // Check current partitions
-        // println!("curr")
-        info!("Checking current partitions");
+        let in_window = self
+            .filters
+            .values()
+            .filter(|filter| filter.end_date > current_date)
+            .any(|filter| filter.filter.contains(short_code));
 
-        for filter in self.filters.values() {
-            if filter.filter.contains(short_code) {
-                return true;
+        in_window
+            || self
+                .future_partition
+                .as_ref()
+                .is_some_and(|future| future.filter.contains(short_code))
+    }
+
+    // Whether an expiry date belongs in the future partition rather than a monthly one
+    fn is_future(&self, expiry_date: NaiveDate) -> bool {
+        self.future_partition
+            .as_ref()
+            .is_some_and(|future| expiry_date >= future.start_date)
+    }
+
+    // Insert a short code into the appropriate filter. Fingerprints are counted,
+    // so removing one code can't take out another code that shares its
+    // fingerprint; inserting a code twice only leaves a false positive behind.
+    pub fn insert(&mut self, short_code: &str, expiry_date: NaiveDate) -> Result<(), FilterError> {
+        if self.is_future(expiry_date) {
+            if let Some(future) = &mut self.future_partition {
+                future.filter.insert_duplicated(short_code)?;
             }
+            if let Some(added) = &mut self.future_rebuild {
+                added.push(short_code.to_string());
+            }
+            return Ok(());
         }
 
-        // Check future partition if exists
-        if let Some(future) = &self.future_partition {
-            if current_date > future.start_date {
-                return future.filter.contains(short_code);
-            }
+        let partition_name = generate_partition_name(expiry_date);
+        if !self.filters.contains_key(&partition_name) {
+            // A month inside the window the filter hasn't seen yet
+            let (start_date, end_date) =
+                month_bounds(expiry_date).ok_or(PartitionError::OutOfRange(expiry_date))?;
+            self.create_partition_filter(partition_name.clone(), start_date, end_date)?;
+        }
+        if let Some(partition_filter) = self.filters.get_mut(&partition_name) {
+            partition_filter.filter.insert_duplicated(short_code)?;
         }
 
-        false
+        Ok(())
     }
 
-    // Insert a short code into the appropriate filter
-    pub fn insert(
+    // Remove a short code from the filter it was inserted into
+    pub fn remove(&mut self, short_code: &str, expiry_date: NaiveDate) -> bool {
+        if self.is_future(expiry_date) {
+            return self
+                .future_partition
+                .as_mut()
+                .is_some_and(|future| future.filter.remove(short_code));
+        }
+        self.filters
+            .get_mut(&generate_partition_name(expiry_date))
+            .is_some_and(|partition_filter| partition_filter.filter.remove(short_code))
+    }
+
+    // Re-insert every live short code from the database, returning how many were added
+    pub async fn rebuild_from_database(&mut self, pool: &PgPool) -> Result<u64, FilterError> {
+        let rows: Vec<(String, NaiveDate)> = sqlx::query_as(
+            "SELECT short_code, expiry_date FROM urls
+             WHERE expiry_date >= CURRENT_DATE",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut inserted = 0u64;
+        for (short_code, expiry_date) in rows {
+            self.insert(&short_code, expiry_date)?;
+            inserted += 1;
+        }
+
+        Ok(inserted)
+    }
+
+    // Month the future filter should hand over next, if it has entered the window
+    pub fn next_rollover(&self, window_end: NaiveDate) -> Option<NaiveDate> {
+        self.future_partition
+            .as_ref()
+            .map(|future| future.start_date)
+            .filter(|start_date| *start_date < window_end)
+    }
+
+    // Copy a month's codes, read back from the database since a filter can't
+    // list what it holds, from the future filter into the month's own filter.
+    // They stay in the future filter: a code read here may never have been
+    // inserted there, and removing it could drop a colliding code's only
+    // fingerprint. The future filter is rebuilt after rollover to shed them.
+    pub fn move_from_future(
         &mut self,
-        short_code: &str,
-        expiry_date: NaiveDate,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let partition_name = generate_partition_name(expiry_date);
-        println!(
-            "inserting into fileter partition_name: {:?}",
-            partition_name
-        );
-        if let Some(partition_filter) = self.filters.get_mut(&partition_name) {
-            partition_filter.filter.insert(short_code)?;
-        } else if let Some(future) = &mut self.future_partition {
-            if expiry_date > future.start_date {
-                future.filter.insert(short_code)?;
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        short_codes: &[String],
+    ) -> Result<(), FilterError> {
+        let partition_name = generate_partition_name(start_date);
+        if !self.filters.contains_key(&partition_name) {
+            self.create_partition_filter(partition_name.clone(), start_date, end_date)?;
+        }
+        if let (Some(partition_filter), Some(future)) = (
+            self.filters.get_mut(&partition_name),
+            self.future_partition.as_mut(),
+        ) {
+            for short_code in short_codes {
+                partition_filter.filter.insert_duplicated(short_code)?;
+            }
+            if future.start_date < end_date {
+                future.start_date = end_date;
             }
         }
 
+        info!(
+            "Copied {} short codes from the future partition into {}",
+            short_codes.len(),
+            partition_name
+        );
         Ok(())
     }
-    // Scope for Improvement
-    // Remove expired partitions and their corresponding filters
-    pub async fn cleanup_expired_partitions(
+
+    // Start recording codes added to the future filter, returning where it
+    // starts, so a replacement built from the database doesn't miss them
+    pub fn begin_future_rebuild(&mut self) -> Option<NaiveDate> {
+        let start_date = self.future_partition.as_ref()?.start_date;
+        self.future_rebuild = Some(Vec::new());
+        Some(start_date)
+    }
+
+    pub fn abort_future_rebuild(&mut self) {
+        self.future_rebuild = None;
+    }
+
+    // Swap in a future filter built from the rows expiring on or after
+    // `start_date`, adding whatever was inserted meanwhile. Returns false if the
+    // filter was replaced or rolled over in the meantime and the rebuild is stale.
+    pub fn finish_future_rebuild(
         &mut self,
-        pool: &PgPool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let current_date = Utc::now().naive_utc().date();
+        start_date: NaiveDate,
+        mut filter: Filter,
+    ) -> Result<bool, FilterError> {
+        let Some(added) = self.future_rebuild.take() else {
+            return Ok(false);
+        };
+        let Some(future) = self
+            .future_partition
+            .as_mut()
+            .filter(|future| future.start_date == start_date)
+        else {
+            return Ok(false);
+        };
+        for short_code in &added {
+            filter.insert_duplicated(short_code)?;
+        }
+        future.filter = filter;
+        Ok(true)
+    }
 
-        // Remove expired filters
+    // Hold a filter for every month from the current one up to `window_end`,
+    // dropping months that have ended
+    pub fn ensure_window(
+        &mut self,
+        current_date: NaiveDate,
+        window_end: NaiveDate,
+    ) -> Result<(), FilterError> {
         self.filters
-            .retain(|_, filter| filter.end_date >= current_date);
-
-        // Create new partition from future if needed
-        if let Some(future) = &self.future_partition {
-            if future.start_date
-                <= current_date
-                    .checked_add_months(chrono::Months::new(36))
-                    .unwrap()
-            {
-                // Move data from future to new partition
-                let new_partition_name = generate_partition_name(future.start_date);
-                let new_filter = Filter::new(1_000_000, 0.01)?;
-
-                // Create new partition in database
-                create_new_partition(
-                    pool,
-                    &new_partition_name,
-                    future.start_date,
-                    future.end_date,
-                )
-                .await?;
-
-                self.filters.insert(
-                    new_partition_name,
-                    PartitionFilter {
-                        filter: new_filter,
-                        start_date: future.start_date,
-                        end_date: future.end_date,
-                    },
-                );
-
-                // Reset future partition
-                self.future_partition = None;
+            .retain(|_, filter| filter.end_date > current_date);
+
+        let (mut start_date, mut end_date) =
+            month_bounds(current_date).ok_or(PartitionError::OutOfRange(current_date))?;
+        while start_date < window_end {
+            let partition_name = generate_partition_name(start_date);
+            if !self.filters.contains_key(&partition_name) {
+                self.create_partition_filter(partition_name, start_date, end_date)?;
             }
+            (start_date, end_date) =
+                month_bounds(end_date).ok_or(PartitionError::OutOfRange(end_date))?;
         }
 
+        if self.future_partition.is_none() {
+            self.future_partition = Some(PartitionFilter {
+                filter: self.new_filter()?,
+                start_date: window_end,
+                end_date: NaiveDate::MAX,
+            });
+        }
         Ok(())
     }
+
+    pub fn window_state(&self) -> WindowState {
+        WindowState {
+            monthly_partitions: self.filters.len(),
+            first_month: self.filters.values().map(|f| f.start_date).min(),
+            last_month: self.filters.values().map(|f| f.start_date).max(),
+            future_start: self.future_partition.as_ref().map(|f| f.start_date),
+            future_items: self.future_partition.as_ref().map_or(0, |f| f.filter.len()),
+        }
+    }
+}
+
+// Months covered by the filter, as reported by the maintenance task
+#[derive(Debug, Clone, Copy)]
+pub struct WindowState {
+    pub monthly_partitions: usize,
+    pub first_month: Option<NaiveDate>,
+    pub last_month: Option<NaiveDate>,
+    pub future_start: Option<NaiveDate>,
+    pub future_items: u64,
 }
 
 // Helper function to generate partition name
@@ -143,20 +304,103 @@ pub fn generate_partition_name(date: NaiveDate) -> String {
     format!("urls_y{}m{:02}", date.year(), date.month())
 }
 
+// First and one-past-last day of the month a date falls in
+pub fn month_bounds(date: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+    let start_date = date.with_day(1)?;
+    let end_date = start_date.checked_add_months(Months::new(1))?;
+    Some((start_date, end_date))
+}
+
+// First day covered by the future partition: the month `window_months` after this one
+pub fn future_start(current_date: NaiveDate, window_months: u32) -> Option<NaiveDate> {
+    current_date
+        .with_day(1)?
+        .checked_add_months(Months::new(window_months))
+}
+
 // Helper function to create a new partition
 pub async fn create_new_partition(
     pool: &PgPool,
     partition_name: &str,
     start_date: NaiveDate,
     end_date: NaiveDate,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let query = format!(
+) -> Result<(), PartitionError> {
+    // Runs for every create, so skip the locking below when there is nothing to do
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+        .bind(partition_name)
+        .fetch_one(pool)
+        .await?;
+    if exists {
+        return Ok(());
+    }
+
+    // Postgres won't add a partition while the DEFAULT partition holds rows in
+    // its range, so detach urls_future, move those rows across and reattach it
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+        "ALTER TABLE urls DETACH PARTITION {}",
+        FUTURE_PARTITION
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
         "CREATE TABLE IF NOT EXISTS {}
          PARTITION OF urls
          FOR VALUES FROM ('{}') TO ('{}');",
         partition_name, start_date, end_date
-    );
+    ))
+    .execute(&mut *tx)
+    .await?;
+    let moved = sqlx::query(&format!(
+        "WITH moved AS (
+             DELETE FROM {} WHERE expiry_date >= $1 AND expiry_date < $2 RETURNING *
+         )
+         INSERT INTO urls SELECT * FROM moved",
+        FUTURE_PARTITION
+    ))
+    .bind(start_date)
+    .bind(end_date)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    sqlx::query(&format!(
+        "ALTER TABLE urls ATTACH PARTITION {} DEFAULT",
+        FUTURE_PARTITION
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
 
-    sqlx::query(&query).execute(pool).await?;
+    if moved > 0 {
+        info!(
+            "Moved {} rows from {} into {}",
+            moved, FUTURE_PARTITION, partition_name
+        );
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Create the monthly partition that a given expiry date falls into. Dates past
+// the window go to urls_future and need no partition of their own.
+pub async fn ensure_partition_for(
+    pool: &PgPool,
+    expiry_date: NaiveDate,
+    window_months: u32,
+) -> Result<(), PartitionError> {
+    let today = Utc::now().date_naive();
+    let window_end = future_start(today, window_months).ok_or(PartitionError::OutOfRange(today))?;
+    if expiry_date >= window_end {
+        return Ok(());
+    }
+
+    let (start_date, end_date) =
+        month_bounds(expiry_date).ok_or(PartitionError::OutOfRange(expiry_date))?;
+
+    create_new_partition(
+        pool,
+        &generate_partition_name(start_date),
+        start_date,
+        end_date,
+    )
+    .await
+}
@@ -1,4 +1,11 @@
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+use crate::aws::persistance::PersistenceError;
+use crate::distributed_filter::{FilterError, PartitionError};
+use crate::request_id;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
@@ -6,31 +13,263 @@ pub enum AppError {
     Database(#[from] sqlx::Error),
     #[error("URL not found or expired")]
     NotFound,
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Invalid {field}: {message}")]
+    Validation {
+        field: &'static str,
+        message: String,
+    },
+    // A request axum couldn't extract: bad JSON, wrong content type, bad path or query
+    #[error("{message}")]
+    Rejected { status: StatusCode, message: String },
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Missing or invalid API key")]
+    Unauthorized,
+    #[error("This API key may not use this endpoint")]
+    Forbidden,
+    #[error("Rate limit exceeded, retry in {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+    #[error("Daily quota of {limit} links exceeded")]
+    QuotaExceeded { limit: u64, reset_secs: u64 },
+    #[error("{0} is unavailable")]
+    Unavailable(&'static str),
     #[error("Redis error: {0}")]
     Redis(#[from] redis::RedisError),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+    #[error(transparent)]
+    Partition(#[from] PartitionError),
+    // Boxed: the S3 error alone would make every `Result<_, AppError>` huge
+    #[error(transparent)]
+    Persistence(Box<PersistenceError>),
+    #[error("Internal error: {0}")]
+    Internal(String),
     #[error("Promethues error: {0}")]
     Prometheus(#[from] prometheus::Error),
 }
 
-impl axum::response::IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
+impl From<PersistenceError> for AppError {
+    fn from(err: PersistenceError) -> Self {
+        AppError::Persistence(Box::new(err))
+    }
+}
+
+// Body of every error response: `{"error": {"code": ..., "message": ..., ...}}`
+#[derive(Serialize)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    // Stable, machine-readable; clients should branch on this rather than the message
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        self.classify().0
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.classify().1
+    }
+
+    fn classify(&self) -> (StatusCode, &'static str) {
+        const INTERNAL: (StatusCode, &str) = (StatusCode::INTERNAL_SERVER_ERROR, "internal");
+        const UNAVAILABLE: (StatusCode, &str) = (StatusCode::SERVICE_UNAVAILABLE, "unavailable");
+        const CONFLICT: (StatusCode, &str) = (StatusCode::CONFLICT, "conflict");
+
         match self {
-            AppError::Database(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database error: {}", err),
-            )
-                .into_response(),
-            AppError::NotFound => StatusCode::NOT_FOUND.into_response(),
-            AppError::Redis(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Redis error: {}", err),
-            )
-                .into_response(),
-            AppError::Prometheus(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Prometheus error: {}", err),
-            )
-                .into_response(),
+            AppError::Database(err) => classify_database(err),
+            AppError::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+            AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            AppError::Validation { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            AppError::Rejected { status, .. } => classify_rejection(*status),
+            AppError::Conflict(_) => CONFLICT,
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+            AppError::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            AppError::QuotaExceeded { .. } => (StatusCode::TOO_MANY_REQUESTS, "quota_exceeded"),
+            AppError::Unavailable(_) | AppError::Redis(_) => UNAVAILABLE,
+            AppError::Partition(err) | AppError::Filter(FilterError::Partition(err)) => {
+                classify_partition(err)
+            }
+            // A full filter can't take new links until it is resized or rolled over
+            AppError::Filter(FilterError::Filter(qfilter::Error::CapacityExceeded)) => UNAVAILABLE,
+            AppError::Filter(FilterError::Database(err)) => classify_database(err),
+            AppError::Persistence(err) if matches!(**err, PersistenceError::S3(_)) => UNAVAILABLE,
+            AppError::Filter(_)
+            | AppError::Persistence(_)
+            | AppError::Internal(_)
+            | AppError::Prometheus(_) => INTERNAL,
         }
     }
+
+    // What the client is told. Server-side failures get a generic message; the
+    // real cause is only logged.
+    fn public_message(&self) -> String {
+        match self.status() {
+            StatusCode::INTERNAL_SERVER_ERROR => "Internal server error".to_string(),
+            StatusCode::SERVICE_UNAVAILABLE => match self {
+                AppError::Unavailable(_) => self.to_string(),
+                _ => "Service temporarily unavailable".to_string(),
+            },
+            StatusCode::CONFLICT => match self {
+                AppError::Conflict(_) => self.to_string(),
+                _ => "Conflict: resource already exists".to_string(),
+            },
+            StatusCode::UNPROCESSABLE_ENTITY => match self.partition_error() {
+                Some(PartitionError::OutOfRange(date)) => {
+                    format!("Invalid expiry_date: no partition can hold {}", date)
+                }
+                _ => self.to_string(),
+            },
+            _ => self.to_string(),
+        }
+    }
+
+    fn partition_error(&self) -> Option<&PartitionError> {
+        match self {
+            AppError::Partition(err) | AppError::Filter(FilterError::Partition(err)) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            AppError::Validation { field, .. } => Some(serde_json::json!({ "field": field })),
+            _ if matches!(self.partition_error(), Some(PartitionError::OutOfRange(_))) => {
+                Some(serde_json::json!({ "field": "expiry_date" }))
+            }
+            AppError::RateLimited { retry_after_secs } => {
+                Some(serde_json::json!({ "retry_after_secs": retry_after_secs }))
+            }
+            AppError::QuotaExceeded { limit, reset_secs } => {
+                Some(serde_json::json!({ "limit": limit, "reset_secs": reset_secs }))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn classify_database(err: &sqlx::Error) -> (StatusCode, &'static str) {
+    match err {
+        sqlx::Error::PoolTimedOut => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        err if err
+            .as_database_error()
+            .is_some_and(|e| e.is_unique_violation()) =>
+        {
+            (StatusCode::CONFLICT, "conflict")
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+    }
+}
+
+fn classify_rejection(status: StatusCode) -> (StatusCode, &'static str) {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => (status, "payload_too_large"),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => (status, "unsupported_media_type"),
+        StatusCode::UNPROCESSABLE_ENTITY => (status, "validation_failed"),
+        StatusCode::INTERNAL_SERVER_ERROR => (status, "internal"),
+        _ => (StatusCode::BAD_REQUEST, "bad_request"),
+    }
+}
+
+fn classify_partition(err: &PartitionError) -> (StatusCode, &'static str) {
+    match err {
+        PartitionError::Database(err) => classify_database(err),
+        PartitionError::OutOfRange(_) => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let request_id = request_id::current();
+
+        if status.is_server_error() {
+            tracing::error!(
+                request_id = request_id.as_deref().unwrap_or("-"),
+                "{}",
+                self
+            );
+        }
+
+        let body = ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.public_message(),
+                request_id,
+                details: self.details(),
+            },
+        };
+        let mut response = (status, Json(body)).into_response();
+
+        let headers = response.headers_mut();
+        match self {
+            AppError::RateLimited { retry_after_secs } => {
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+            }
+            AppError::QuotaExceeded { limit, reset_secs } => {
+                headers.insert("x-quota-limit", HeaderValue::from(limit));
+                headers.insert("x-quota-remaining", HeaderValue::from(0));
+                headers.insert("x-quota-reset", HeaderValue::from(reset_secs));
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(reset_secs));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn out_of_range_partition_names_the_date() {
+        let date = NaiveDate::from_ymd_opt(2025, 3, 1).unwrap();
+        for err in [
+            AppError::Partition(PartitionError::OutOfRange(date)),
+            AppError::Filter(FilterError::Partition(PartitionError::OutOfRange(date))),
+        ] {
+            assert_eq!(err.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                err.public_message(),
+                "Invalid expiry_date: no partition can hold 2025-03-01"
+            );
+            assert_eq!(
+                err.details(),
+                Some(serde_json::json!({ "field": "expiry_date" }))
+            );
+        }
+    }
+
+    #[test]
+    fn other_unprocessable_errors_keep_their_message() {
+        let err = AppError::Validation {
+            field: "redirect_type",
+            message: "unsupported redirect_type 303".to_string(),
+        };
+        assert_eq!(
+            err.public_message(),
+            "Invalid redirect_type: unsupported redirect_type 303"
+        );
+
+        let err = AppError::Rejected {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message: "missing field `long_url`".to_string(),
+        };
+        assert_eq!(err.code(), "validation_failed");
+        assert_eq!(err.public_message(), "missing field `long_url`");
+    }
 }
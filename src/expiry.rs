@@ -0,0 +1,360 @@
+use chrono::{DateTime, Duration, Months, NaiveDate, Utc};
+
+use crate::config::LinksConfig;
+use crate::errors::AppError;
+use crate::models::{CreateUrl, ExpiresAt};
+
+// Stored as the expiry_date of links that never expire. Beyond the monthly
+// window, so the filter keeps these in its future partition.
+pub const NEVER_EXPIRES: NaiveDate = NaiveDate::from_ymd_opt(9999, 12, 31).unwrap();
+
+// When a link stops redirecting. Partitions stay monthly and keyed on
+// expiry_date; `expires_at` narrows that down to an exact instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    pub expiry_date: NaiveDate,
+    // None: live through the end of expiry_date (UTC)
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Expiry {
+    pub fn never() -> Self {
+        Expiry {
+            expiry_date: NEVER_EXPIRES,
+            expires_at: None,
+        }
+    }
+
+    pub fn whole_day(expiry_date: NaiveDate) -> Self {
+        Expiry {
+            expiry_date,
+            expires_at: None,
+        }
+    }
+
+    pub fn exact(expires_at: DateTime<Utc>) -> Self {
+        Expiry {
+            expiry_date: expires_at.date_naive(),
+            expires_at: Some(expires_at),
+        }
+    }
+
+    pub fn is_never(&self) -> bool {
+        self.expiry_date == NEVER_EXPIRES
+    }
+
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at > now,
+            None => self.expiry_date >= now.date_naive(),
+        }
+    }
+
+    // The instant the link stops redirecting
+    fn ends_at(&self) -> Option<DateTime<Utc>> {
+        match self.expires_at {
+            Some(expires_at) => Some(expires_at),
+            None => Some(self.expiry_date.succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc()),
+        }
+    }
+
+    // `expiry_date` as shown in API responses
+    pub fn display_date(&self) -> String {
+        if self.is_never() {
+            "never".to_string()
+        } else {
+            self.expiry_date.to_string()
+        }
+    }
+}
+
+// Server-side bounds on how long a link may live
+#[derive(Debug, Clone, Copy)]
+pub struct ExpiryPolicy {
+    min_lifetime: Duration,
+    max_lifetime: Duration,
+    allow_never: bool,
+}
+
+impl ExpiryPolicy {
+    pub fn new(config: &LinksConfig) -> Self {
+        ExpiryPolicy {
+            min_lifetime: Duration::seconds(config.min_lifetime_secs as i64),
+            max_lifetime: Duration::days(config.max_lifetime_days as i64),
+            allow_never: config.allow_never_expiring,
+        }
+    }
+
+    // Work out the expiry requested by a create body
+    pub fn resolve(&self, payload: &CreateUrl, now: DateTime<Utc>) -> Result<Expiry, AppError> {
+        let given = [
+            payload.months_valid.is_some(),
+            payload.expires_at.is_some(),
+            payload.expires_in.is_some(),
+        ]
+        .into_iter()
+        .filter(|given| *given)
+        .count();
+        if given > 1 {
+            return Err(AppError::Validation {
+                field: "expires_at",
+                message: "give only one of months_valid, expires_at and expires_in".to_string(),
+            });
+        }
+
+        if let Some(expires_at) = payload.expires_at {
+            return match expires_at {
+                ExpiresAt::Never if self.allow_never => Ok(Expiry::never()),
+                ExpiresAt::Never => Err(AppError::Validation {
+                    field: "expires_at",
+                    message: "links that never expire are not allowed".to_string(),
+                }),
+                ExpiresAt::At(at) => self.check("expires_at", Expiry::exact(at), now),
+            };
+        }
+
+        if let Some(expires_in) = payload.expires_in {
+            let lifetime =
+                Duration::days(expires_in.days as i64) + Duration::hours(expires_in.hours as i64);
+            let at = now
+                .checked_add_signed(lifetime)
+                .ok_or_else(|| out_of_range("expires_in"))?;
+            return self.check("expires_in", Expiry::exact(at), now);
+        }
+
+        let expiry_date = now
+            .date_naive()
+            .checked_add_months(Months::new(payload.months_valid.unwrap_or(1)))
+            .ok_or_else(|| out_of_range("months_valid"))?;
+        self.check("months_valid", Expiry::whole_day(expiry_date), now)
+    }
+
+    // Reject expiries sooner than the minimum or later than the maximum lifetime
+    pub fn check(
+        &self,
+        field: &'static str,
+        expiry: Expiry,
+        now: DateTime<Utc>,
+    ) -> Result<Expiry, AppError> {
+        if expiry.is_never() {
+            return if self.allow_never {
+                Ok(expiry)
+            } else {
+                Err(out_of_range(field))
+            };
+        }
+
+        let lifetime = expiry.ends_at().ok_or_else(|| out_of_range(field))? - now;
+        if lifetime < self.min_lifetime {
+            return Err(AppError::Validation {
+                field,
+                message: format!(
+                    "link must stay live for at least {} seconds",
+                    self.min_lifetime.num_seconds()
+                ),
+            });
+        }
+        if lifetime > self.max_lifetime {
+            return Err(AppError::Validation {
+                field,
+                message: format!(
+                    "link may stay live for at most {} days",
+                    self.max_lifetime.num_days()
+                ),
+            });
+        }
+        Ok(expiry)
+    }
+}
+
+fn out_of_range(field: &'static str) -> AppError {
+    AppError::Validation {
+        field,
+        message: "expiry date out of range".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ExpiresIn;
+    use chrono::TimeZone;
+
+    fn policy(allow_never: bool) -> ExpiryPolicy {
+        ExpiryPolicy::new(&LinksConfig {
+            allow_never_expiring: allow_never,
+            ..LinksConfig::default()
+        })
+    }
+
+    fn base() -> CreateUrl {
+        CreateUrl {
+            long_url: "https://example.com".to_string(),
+            months_valid: None,
+            expires_at: None,
+            expires_in: None,
+            custom_short_code: None,
+            redirect_type: None,
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 31, 12, 0, 0).unwrap()
+    }
+
+    fn rejected_field(result: Result<Expiry, AppError>) -> &'static str {
+        match result {
+            Err(AppError::Validation { field, .. }) => field,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_to_one_month() {
+        let expiry = policy(true).resolve(&base(), now()).unwrap();
+        assert_eq!(expiry, Expiry::whole_day(date(2025, 2, 28)));
+    }
+
+    #[test]
+    fn month_overflow_clamps_to_the_end_of_the_month() {
+        let payload = CreateUrl {
+            months_valid: Some(13),
+            ..base()
+        };
+        let expiry = policy(true).resolve(&payload, now()).unwrap();
+        assert_eq!(expiry.expiry_date, date(2026, 2, 28));
+    }
+
+    #[test]
+    fn out_of_range_months_are_rejected() {
+        let payload = CreateUrl {
+            months_valid: Some(u32::MAX),
+            ..base()
+        };
+        assert_eq!(
+            rejected_field(policy(true).resolve(&payload, now())),
+            "months_valid"
+        );
+    }
+
+    #[test]
+    fn only_one_expiry_may_be_given() {
+        let at = ExpiresAt::At(now() + Duration::days(2));
+        let expires_in = ExpiresIn { days: 2, hours: 0 };
+        for payload in [
+            CreateUrl {
+                months_valid: Some(1),
+                expires_at: Some(at),
+                ..base()
+            },
+            CreateUrl {
+                months_valid: Some(1),
+                expires_in: Some(expires_in),
+                ..base()
+            },
+            CreateUrl {
+                expires_at: Some(at),
+                expires_in: Some(expires_in),
+                ..base()
+            },
+        ] {
+            assert_eq!(
+                rejected_field(policy(true).resolve(&payload, now())),
+                "expires_at"
+            );
+        }
+    }
+
+    #[test]
+    fn expires_in_is_exact() {
+        let payload = CreateUrl {
+            expires_in: Some(ExpiresIn { days: 1, hours: 6 }),
+            ..base()
+        };
+        let expiry = policy(true).resolve(&payload, now()).unwrap();
+        assert_eq!(expiry, Expiry::exact(now() + Duration::hours(30)));
+    }
+
+    #[test]
+    fn lifetime_below_the_minimum_is_rejected() {
+        let payload = CreateUrl {
+            expires_at: Some(ExpiresAt::At(now() + Duration::seconds(299))),
+            ..base()
+        };
+        assert_eq!(
+            rejected_field(policy(true).resolve(&payload, now())),
+            "expires_at"
+        );
+
+        let payload = CreateUrl {
+            expires_in: Some(ExpiresIn { days: 0, hours: 0 }),
+            ..base()
+        };
+        assert_eq!(
+            rejected_field(policy(true).resolve(&payload, now())),
+            "expires_in"
+        );
+    }
+
+    #[test]
+    fn lifetime_above_the_maximum_is_rejected() {
+        let policy = policy(true);
+        let max = Duration::days(3650);
+        let payload = CreateUrl {
+            expires_at: Some(ExpiresAt::At(now() + max)),
+            ..base()
+        };
+        assert!(policy.resolve(&payload, now()).is_ok());
+
+        let payload = CreateUrl {
+            expires_at: Some(ExpiresAt::At(now() + max + Duration::seconds(1))),
+            ..base()
+        };
+        assert_eq!(
+            rejected_field(policy.resolve(&payload, now())),
+            "expires_at"
+        );
+
+        // Ten years of months is longer than 3650 days once leap days are counted
+        let payload = CreateUrl {
+            months_valid: Some(120),
+            ..base()
+        };
+        assert_eq!(
+            rejected_field(policy.resolve(&payload, now())),
+            "months_valid"
+        );
+    }
+
+    #[test]
+    fn never_depends_on_the_policy() {
+        let payload = CreateUrl {
+            expires_at: Some(ExpiresAt::Never),
+            ..base()
+        };
+        let expiry = policy(true).resolve(&payload, now()).unwrap();
+        assert!(expiry.is_never());
+        assert_eq!(expiry.display_date(), "never");
+        assert!(expiry.is_live(now() + Duration::days(365 * 100)));
+
+        assert_eq!(
+            rejected_field(policy(false).resolve(&payload, now())),
+            "expires_at"
+        );
+        assert_eq!(
+            rejected_field(policy(false).check("expiry_date", Expiry::never(), now())),
+            "expiry_date"
+        );
+    }
+
+    #[test]
+    fn whole_day_expiry_is_live_through_the_end_of_the_day() {
+        let expiry = Expiry::whole_day(date(2025, 1, 31));
+        assert!(expiry.is_live(Utc.with_ymd_and_hms(2025, 1, 31, 23, 59, 59).unwrap()));
+        assert!(!expiry.is_live(Utc.with_ymd_and_hms(2025, 2, 1, 0, 0, 0).unwrap()));
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+}
@@ -0,0 +1,355 @@
+use axum::extract::State;
+use chrono::{NaiveDate, Utc};
+use sqlx::{PgConnection, PgPool};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::auth::ApiKey;
+use crate::config::FilterConfig;
+use crate::distributed_filter::{DistributedFilter, FilterError, FUTURE_PARTITION};
+use crate::errors::AppError;
+use crate::extract::{Json, Query};
+use crate::filter_sync::{FilterEvent, FilterSync};
+use crate::metrics::{
+    FILTER_AUDIT_LAST_SUCCESS, FILTER_AUDIT_MISSING, FILTER_AUDIT_REPAIRED, FILTER_FILL_RATIO,
+    FILTER_OBSERVED_FALSE_POSITIVE_RATE,
+};
+use crate::models::{AuditQuery, AuditReport, PartitionAudit};
+use crate::shutdown::Shutdown;
+use crate::AppState;
+
+// Same length as generated short codes
+const PROBE_CODE_LEN: usize = 8;
+// Held while an instance samples `urls` for its scheduled audit, so replicas
+// take turns instead of all reading the partitions at once
+const AUDIT_LOCK_KEY: i64 = 0x6361_7267_6175_6474;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuditSettings {
+    pub sample_size: u32,
+    pub probes: u32,
+    pub repair: bool,
+}
+
+impl AuditSettings {
+    pub fn new(config: &FilterConfig) -> Self {
+        AuditSettings {
+            sample_size: config.audit_sample_size,
+            probes: config.audit_probes,
+            repair: config.audit_repair,
+        }
+    }
+}
+
+// Check a random sample of live rows from every `urls` partition against this
+// instance's filter, and estimate its false-positive rate from random codes.
+// Database work runs without the filter lock so redirects aren't held up.
+pub async fn audit_filter(
+    filter: &Mutex<DistributedFilter>,
+    pool: &PgPool,
+    sync: &FilterSync,
+    settings: AuditSettings,
+    current_date: NaiveDate,
+    take_turns: bool,
+) -> Result<AuditReport, FilterError> {
+    let samples = {
+        let mut conn = pool.acquire().await?;
+        if take_turns {
+            sqlx::query("SELECT pg_advisory_lock($1)")
+                .bind(AUDIT_LOCK_KEY)
+                .execute(&mut *conn)
+                .await?;
+        }
+        let samples = sample_live_rows(&mut conn, settings.sample_size, current_date).await;
+        // Session lock, so it is released on the same connection
+        if take_turns {
+            sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(AUDIT_LOCK_KEY)
+                .execute(&mut *conn)
+                .await?;
+        }
+        samples?
+    };
+
+    let mut partitions = BTreeMap::new();
+    let mut repairs = Vec::new();
+    for (table, rows) in samples {
+        let mut audit = PartitionAudit {
+            partition: table.clone(),
+            sampled: rows.len() as u64,
+            missing: 0,
+            repaired: 0,
+            filter_items: None,
+            fill_ratio: None,
+            false_positives: None,
+            observed_false_positive_rate: None,
+        };
+        let mut filter = filter.lock().await;
+        for (short_code, expiry_date) in rows {
+            if filter.contains(&short_code, current_date) {
+                continue;
+            }
+            audit.missing += 1;
+            if settings.repair {
+                filter.insert(&short_code, expiry_date)?;
+                audit.repaired += 1;
+                repairs.push(FilterEvent::insert(&short_code, expiry_date));
+            }
+        }
+        partitions.insert(table, audit);
+    }
+    sync.publish(&repairs).await;
+
+    let probes: Vec<String> = (0..settings.probes)
+        .map(|_| nanoid::nanoid!(PROBE_CODE_LEN))
+        .collect();
+    // Probes each partition filter accepted, and the union a redirect checks
+    let (partition_hits, hits, configured_false_positive_rate) = {
+        let filter = filter.lock().await;
+        let mut partition_hits = Vec::new();
+        for (name, partition_filter) in filter
+            .filters
+            .iter()
+            .filter(|(_, f)| f.end_date > current_date)
+            .map(|(name, f)| (name.as_str(), f))
+            .chain(
+                filter
+                    .future_partition
+                    .iter()
+                    .map(|f| (FUTURE_PARTITION, f)),
+            )
+        {
+            let audit = partitions
+                .entry(name.to_string())
+                .or_insert_with(|| PartitionAudit {
+                    partition: name.to_string(),
+                    sampled: 0,
+                    missing: 0,
+                    repaired: 0,
+                    filter_items: None,
+                    fill_ratio: None,
+                    false_positives: None,
+                    observed_false_positive_rate: None,
+                });
+            let items = partition_filter.filter.len();
+            audit.filter_items = Some(items);
+            audit.fill_ratio = Some(items as f64 / partition_filter.filter.capacity() as f64);
+
+            let accepted: Vec<&str> = probes
+                .iter()
+                .map(String::as_str)
+                .filter(|code| partition_filter.filter.contains(*code))
+                .collect();
+            partition_hits.push((name.to_string(), accepted));
+        }
+
+        let hits: Vec<String> = probes
+            .iter()
+            .filter(|code| filter.contains(code, current_date))
+            .cloned()
+            .collect();
+        (partition_hits, hits, filter.config.false_positive_rate)
+    };
+
+    // A random code could, however unlikely, belong to a live link
+    let live: HashSet<String> = sqlx::query_scalar(
+        "SELECT short_code FROM urls
+         WHERE short_code = ANY($1) AND expiry_date >= $2",
+    )
+    .bind(&hits)
+    .bind(current_date)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    let negatives = settings.probes as u64 - live.len() as u64;
+    let rate = |false_positives: u64| {
+        if negatives == 0 {
+            0.0
+        } else {
+            false_positives as f64 / negatives as f64
+        }
+    };
+
+    for (name, accepted) in &partition_hits {
+        let false_positives = accepted
+            .iter()
+            .filter(|code| !live.contains(**code))
+            .count() as u64;
+        if let Some(audit) = partitions.get_mut(name) {
+            audit.false_positives = Some(false_positives);
+            audit.observed_false_positive_rate = Some(rate(false_positives));
+        }
+    }
+    let false_positives = hits.iter().filter(|code| !live.contains(*code)).count() as u64;
+    // A lookup checks every filter, so the chance that one of them accepts an
+    // unused code grows with their number
+    let expected_false_positive_rate =
+        1.0 - (1.0 - configured_false_positive_rate).powi(partition_hits.len() as i32);
+
+    let partitions: Vec<PartitionAudit> = partitions.into_values().collect();
+    Ok(AuditReport {
+        audited_at: Utc::now(),
+        sampled: partitions.iter().map(|p| p.sampled).sum(),
+        missing: partitions.iter().map(|p| p.missing).sum(),
+        repaired: partitions.iter().map(|p| p.repaired).sum(),
+        partitions,
+        probes: settings.probes as u64,
+        false_positives,
+        observed_false_positive_rate: rate(false_positives),
+        configured_false_positive_rate,
+        expected_false_positive_rate,
+    })
+}
+
+// Up to `sample_size` live rows from each monthly partition and the future one
+async fn sample_live_rows(
+    conn: &mut PgConnection,
+    sample_size: u32,
+    current_date: NaiveDate,
+) -> Result<Vec<(String, Vec<(String, NaiveDate)>)>, sqlx::Error> {
+    let tables: Vec<(String, f32)> = sqlx::query_as(
+        "SELECT c.relname::text, c.reltuples FROM pg_inherits i
+         JOIN pg_class c ON c.oid = i.inhrelid
+         WHERE i.inhparent = 'urls'::regclass
+         ORDER BY c.relname",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut samples = Vec::with_capacity(tables.len());
+    for (table, row_estimate) in tables {
+        if !table.starts_with("urls_y") && table != FUTURE_PARTITION {
+            continue;
+        }
+        // Names come from the catalog, so they only need quoting. Sampling pages
+        // keeps the audit from reading and sorting whole partitions.
+        let rows: Vec<(String, NaiveDate)> = sqlx::query_as(&format!(
+            "SELECT short_code, expiry_date FROM \"{}\" TABLESAMPLE SYSTEM ($3::real)
+             WHERE expiry_date >= $1
+             LIMIT $2",
+            table
+        ))
+        .bind(current_date)
+        .bind(sample_size as i64)
+        .bind(sample_percent(sample_size, row_estimate))
+        .fetch_all(&mut *conn)
+        .await?;
+        samples.push((table, rows));
+    }
+    Ok(samples)
+}
+
+// Share of a partition's pages to sample for about `sample_size` rows, with
+// room for expired rows. `reltuples` is -1 until the table is first analyzed.
+fn sample_percent(sample_size: u32, row_estimate: f32) -> f32 {
+    if row_estimate <= 0.0 {
+        return 100.0;
+    }
+    (sample_size as f32 * 2.0 / row_estimate * 100.0).clamp(0.01, 100.0)
+}
+
+fn record_metrics(report: &AuditReport) {
+    // Reset so partitions that have been dropped stop being reported
+    FILTER_AUDIT_MISSING.reset();
+    FILTER_FILL_RATIO.reset();
+    FILTER_OBSERVED_FALSE_POSITIVE_RATE.reset();
+    for partition in &report.partitions {
+        FILTER_AUDIT_MISSING
+            .with_label_values(&[&partition.partition])
+            .set(partition.missing as f64);
+        if let Some(fill_ratio) = partition.fill_ratio {
+            FILTER_FILL_RATIO
+                .with_label_values(&[&partition.partition])
+                .set(fill_ratio);
+        }
+        if let Some(rate) = partition.observed_false_positive_rate {
+            FILTER_OBSERVED_FALSE_POSITIVE_RATE
+                .with_label_values(&[&partition.partition])
+                .set(rate);
+        }
+    }
+    FILTER_AUDIT_REPAIRED.inc_by(report.repaired as f64);
+    FILTER_AUDIT_LAST_SUCCESS.set(report.audited_at.timestamp() as f64);
+}
+
+fn log_report(report: &AuditReport) {
+    if report.missing > 0 {
+        tracing::warn!(
+            "Filter audit: {} of {} sampled live codes missing, {} repaired",
+            report.missing,
+            report.sampled,
+            report.repaired
+        );
+    }
+    tracing::info!(
+        "Filter audit: {} partitions, observed false-positive rate {:.4} (expected {:.4} across all filters, {} per filter)",
+        report.partitions.len(),
+        report.observed_false_positive_rate,
+        report.expected_false_positive_rate,
+        report.configured_false_positive_rate
+    );
+}
+
+pub async fn run_filter_audit(
+    filter: Arc<Mutex<DistributedFilter>>,
+    pool: PgPool,
+    sync: FilterSync,
+    settings: AuditSettings,
+    interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+
+        // Every instance audits its own filter; only the sampling takes turns
+        match audit_filter(
+            &filter,
+            &pool,
+            &sync,
+            settings,
+            Utc::now().date_naive(),
+            true,
+        )
+        .await
+        {
+            Ok(report) => {
+                record_metrics(&report);
+                log_report(&report);
+            }
+            Err(e) => tracing::error!("Filter audit failed: {}", e),
+        }
+    }
+}
+
+// On-demand audit for admin keys; `?repair=true` puts missing codes back
+pub async fn audit(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditReport>, AppError> {
+    api_key.require_admin()?;
+
+    let settings = AuditSettings {
+        repair: query.repair.unwrap_or(state.audit.repair),
+        ..state.audit
+    };
+    let report = audit_filter(
+        &state.distributed_filter,
+        &state.db.writer,
+        &state.filter_sync,
+        settings,
+        Utc::now().date_naive(),
+        false,
+    )
+    .await?;
+    record_metrics(&report);
+    log_report(&report);
+    Ok(Json(report))
+}
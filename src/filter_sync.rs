@@ -0,0 +1,264 @@
+use chrono::NaiveDate;
+use redis::streams::{StreamId, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, RedisResult};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::aws::persistance::DistributedFilterPersistence;
+use crate::config::FilterConfig;
+use crate::distributed_filter::{DistributedFilter, FilterError};
+use crate::metrics::{FILTER_SYNC_EVENTS_APPLIED, FILTER_SYNC_PUBLISH_FAILURES};
+use crate::redis::RedisManager;
+use crate::shutdown::Shutdown;
+
+// Position before the first event of a feed
+pub const FEED_START: &str = "0-0";
+const READ_BLOCK_MS: usize = 5000;
+const READ_BATCH: usize = 500;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum FilterSyncError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterOp {
+    Insert,
+    Remove,
+}
+
+// One change to the filter, as carried on the Redis stream
+#[derive(Debug, Clone)]
+pub struct FilterEvent {
+    pub op: FilterOp,
+    pub short_code: String,
+    pub expiry_date: NaiveDate,
+}
+
+impl FilterEvent {
+    pub fn insert(short_code: &str, expiry_date: NaiveDate) -> Self {
+        FilterEvent {
+            op: FilterOp::Insert,
+            short_code: short_code.to_string(),
+            expiry_date,
+        }
+    }
+
+    pub fn remove(short_code: &str, expiry_date: NaiveDate) -> Self {
+        FilterEvent {
+            op: FilterOp::Remove,
+            short_code: short_code.to_string(),
+            expiry_date,
+        }
+    }
+
+    // Stream entry fields; `origin` lets the publisher skip its own events
+    pub fn fields(&self, origin: &str) -> [(&'static str, String); 4] {
+        let op = match self.op {
+            FilterOp::Insert => "insert",
+            FilterOp::Remove => "remove",
+        };
+        [
+            ("op", op.to_string()),
+            ("code", self.short_code.clone()),
+            ("expiry", self.expiry_date.to_string()),
+            ("origin", origin.to_string()),
+        ]
+    }
+
+    // The event and the instance that published it
+    fn parse(entry: &StreamId) -> Option<(Self, String)> {
+        let op = match entry.get::<String>("op")?.as_str() {
+            "insert" => FilterOp::Insert,
+            "remove" => FilterOp::Remove,
+            _ => return None,
+        };
+        let event = FilterEvent {
+            op,
+            short_code: entry.get("code")?,
+            expiry_date: entry.get::<String>("expiry")?.parse().ok()?,
+        };
+        Some((event, entry.get("origin")?))
+    }
+
+    pub fn apply(&self, filter: &mut DistributedFilter) -> Result<(), FilterError> {
+        match self.op {
+            // A replayed insert adds a second fingerprint, which only costs a false positive
+            FilterOp::Insert => filter.insert(&self.short_code, self.expiry_date),
+            FilterOp::Remove => {
+                filter.remove(&self.short_code, self.expiry_date);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Shares filter changes with the other instances through a Redis stream
+#[derive(Clone)]
+pub struct FilterSync {
+    redis: Option<RedisManager>,
+    instance_id: String,
+    max_events: usize,
+}
+
+impl FilterSync {
+    pub fn new(redis: RedisManager, max_events: usize) -> Self {
+        FilterSync {
+            redis: Some(redis),
+            instance_id: nanoid::nanoid!(12),
+            max_events,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        FilterSync {
+            redis: None,
+            instance_id: String::new(),
+            max_events: 0,
+        }
+    }
+
+    // Announce changes already applied to this instance's filter. A failure only
+    // leaves the other instances behind, so it is counted and logged, not returned.
+    pub async fn publish(&self, events: &[FilterEvent]) {
+        let Some(redis) = &self.redis else {
+            return;
+        };
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = redis
+            .publish_filter_events(&self.instance_id, events, self.max_events)
+            .await
+        {
+            FILTER_SYNC_PUBLISH_FAILURES.inc_by(events.len() as f64);
+            error!("Failed to publish {} filter events: {}", events.len(), e);
+        }
+    }
+}
+
+// Fill the filter before serving and return the feed position it reflects. The
+// latest snapshot is used if it records its position and the feed still holds
+// every event after it; otherwise the filter is rebuilt from the database.
+pub async fn load_filter(
+    filter: &Mutex<DistributedFilter>,
+    persistence: &DistributedFilterPersistence,
+    pool: &PgPool,
+    sync: &FilterSync,
+    filter_config: &FilterConfig,
+) -> Result<String, FilterSyncError> {
+    if let Some(redis) = &sync.redis {
+        match persistence.load_latest_snapshot(filter_config).await {
+            Ok(Some(snapshot)) => match snapshot.last_event_id.clone() {
+                Some(position) if feed_covers(redis, &position).await? => {
+                    info!(
+                        "Restored filter snapshot, replaying events after {}",
+                        position
+                    );
+                    *filter.lock().await = snapshot;
+                    return Ok(position);
+                }
+                _ => warn!("Filter snapshot is older than the change feed, rebuilding instead"),
+            },
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Could not load a filter snapshot, rebuilding instead: {}",
+                e
+            ),
+        }
+    }
+
+    // Taken before the rebuild so changes made while it runs are replayed
+    let position = match &sync.redis {
+        Some(redis) => redis.filter_event_bound(true).await?,
+        None => None,
+    }
+    .unwrap_or_else(|| FEED_START.to_string());
+
+    let mut filter = filter.lock().await;
+    let inserted = filter.rebuild_from_database(pool).await?;
+    filter.last_event_id = Some(position.clone());
+    info!("Loaded {} short codes into the filter", inserted);
+    Ok(position)
+}
+
+// Whether every event after `position` is still in the stream
+async fn feed_covers(redis: &RedisManager, position: &str) -> RedisResult<bool> {
+    Ok(match redis.filter_event_bound(false).await? {
+        Some(oldest) => matches!(
+            (parse_id(&oldest), parse_id(position)),
+            (Some(oldest), Some(position)) if oldest <= position
+        ),
+        None => position == FEED_START,
+    })
+}
+
+// Stream ids are `<ms>-<seq>` and must be compared numerically
+fn parse_id(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+// Apply the other instances' filter changes from `position` onwards
+pub async fn run_filter_sync(
+    filter: Arc<Mutex<DistributedFilter>>,
+    sync: FilterSync,
+    mut position: String,
+    mut shutdown: Shutdown,
+) {
+    let Some(redis) = sync.redis.clone() else {
+        return;
+    };
+    let key = redis.filter_events_key();
+    let options = StreamReadOptions::default()
+        .block(READ_BLOCK_MS)
+        .count(READ_BATCH);
+
+    loop {
+        match redis.dedicated_connection().await {
+            Ok(mut conn) => loop {
+                let (keys, ids) = ([&key], [&position]);
+                let reply: RedisResult<Option<StreamReadReply>> = tokio::select! {
+                    reply = conn.xread_options(&keys, &ids, &options) => reply,
+                    _ = shutdown.wait() => return,
+                };
+                let entries: Vec<StreamId> = match reply {
+                    Ok(Some(reply)) => reply.keys.into_iter().flat_map(|key| key.ids).collect(),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Filter sync read failed, reconnecting: {}", e);
+                        break;
+                    }
+                };
+
+                let mut filter = filter.lock().await;
+                for entry in &entries {
+                    match FilterEvent::parse(entry) {
+                        // Applied locally when it was published
+                        Some((_, origin)) if origin == sync.instance_id => {}
+                        Some((event, _)) => match event.apply(&mut filter) {
+                            Ok(()) => FILTER_SYNC_EVENTS_APPLIED.inc(),
+                            Err(e) => error!("Failed to apply filter event {}: {}", entry.id, e),
+                        },
+                        None => warn!("Skipping malformed filter event {}", entry.id),
+                    }
+                    position = entry.id.clone();
+                }
+                filter.last_event_id = Some(position.clone());
+            },
+            Err(e) => error!("Filter sync could not connect to Redis: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.wait() => return,
+        }
+    }
+}
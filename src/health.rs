@@ -0,0 +1,130 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::config::FilterTrust;
+use crate::errors::AppError;
+use crate::AppState;
+
+// Upper bound for a single dependency check so a hung backend can't hang the probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    fn ok() -> Self {
+        CheckResult {
+            status: "ok",
+            error: None,
+        }
+    }
+
+    fn failed(status: &'static str, error: impl ToString) -> Self {
+        CheckResult {
+            status,
+            error: Some(error.to_string()),
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+// Liveness: the process is up and serving requests
+pub async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Answer 503 until the filter has loaded. Writes made before then would be lost
+// when a snapshot replaces the filter, and strict redirects would 404.
+pub async fn require_filter(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.filter_ready.load(Ordering::Acquire) {
+        return next.run(req).await;
+    }
+    let is_redirect = !req.uri().path().starts_with("/api/");
+    if is_redirect && state.filter_trust == FilterTrust::Disabled {
+        return next.run(req).await;
+    }
+    AppError::Unavailable("filter").into_response()
+}
+
+// Readiness: every dependency needed to serve redirects is available
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+
+    // Both calls are async, so the timeout can cancel either one. Run them
+    // together so a slow dependency doesn't delay the other's result.
+    let (database, redis) = tokio::join!(
+        tokio::time::timeout(
+            CHECK_TIMEOUT,
+            sqlx::query("SELECT 1").execute(&state.db.writer),
+        ),
+        tokio::time::timeout(CHECK_TIMEOUT, state.redis.ping()),
+    );
+    checks.insert(
+        "database",
+        match database {
+            Ok(Ok(_)) => CheckResult::ok(),
+            Ok(Err(e)) => CheckResult::failed("error", e),
+            Err(_) => CheckResult::failed("timeout", "database check timed out"),
+        },
+    );
+    checks.insert(
+        "redis",
+        match redis {
+            Ok(Ok(())) => CheckResult::ok(),
+            // The connection's own response timeout usually fires first
+            Ok(Err(e)) if e.is_timeout() => CheckResult::failed("timeout", e),
+            Ok(Err(e)) => CheckResult::failed("error", e),
+            Err(_) => CheckResult::failed("timeout", "redis check timed out"),
+        },
+    );
+
+    checks.insert(
+        "filter",
+        if state.filter_ready.load(Ordering::Acquire) {
+            CheckResult::ok()
+        } else {
+            CheckResult::failed("loading", "distributed filter is still loading")
+        },
+    );
+
+    let ready = checks.values().all(CheckResult::is_ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            status: if ready { "ok" } else { "unavailable" },
+            checks,
+        }),
+    )
+}
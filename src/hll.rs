@@ -0,0 +1,148 @@
+// HyperLogLog sketch for approximate unique visitor counts. Rollup rows store
+// the raw registers so sketches for any range can be merged and estimated.
+
+// 2^10 registers: about 3% standard error in 1 KiB per sketch
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Restore a stored sketch; anything of the wrong size starts empty
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() == REGISTERS {
+            HyperLogLog {
+                registers: bytes.to_vec(),
+            }
+        } else {
+            Self::new()
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    // Add an already well-mixed 64-bit hash, e.g. the click `visitor_hash`
+    pub fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let rest = hash << PRECISION;
+        let rank = (rest.leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        // Linear counting is more accurate while many registers are still empty
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    // Well-mixed hashes, like the ones the click writer feeds in
+    fn hash(value: u64) -> u64 {
+        let digest = Sha256::digest(value.to_be_bytes());
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+
+    fn sketch(values: std::ops::Range<u64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        for value in values {
+            hll.insert(hash(value));
+        }
+        hll
+    }
+
+    fn assert_close(estimate: u64, actual: u64, tolerance: f64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(
+            error <= tolerance,
+            "estimate {} for {} is off by {:.1}%",
+            estimate,
+            actual,
+            error * 100.0
+        );
+    }
+
+    #[test]
+    fn empty_sketch_estimates_zero() {
+        assert_eq!(HyperLogLog::new().estimate(), 0);
+    }
+
+    #[test]
+    fn estimate_is_within_error() {
+        // Three standard errors of about 3.2% each
+        assert_close(sketch(0..10_000).estimate(), 10_000, 0.1);
+        assert_close(sketch(0..100).estimate(), 100, 0.1);
+    }
+
+    #[test]
+    fn duplicates_are_not_counted_twice() {
+        let mut hll = sketch(0..10_000);
+        let before = hll.estimate();
+        for value in 0..10_000 {
+            hll.insert(hash(value));
+        }
+        assert_eq!(hll.estimate(), before);
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let original = sketch(0..10_000);
+        let mut merged = original.clone();
+        merged.merge(&original);
+        merged.merge(&original);
+        assert_eq!(merged.as_bytes(), original.as_bytes());
+    }
+
+    #[test]
+    fn merge_matches_a_single_sketch() {
+        let mut merged = sketch(0..6_000);
+        merged.merge(&sketch(4_000..10_000));
+        assert_eq!(merged.as_bytes(), sketch(0..10_000).as_bytes());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let original = sketch(0..1_000);
+        let restored = HyperLogLog::from_bytes(original.as_bytes());
+        assert_eq!(restored.as_bytes(), original.as_bytes());
+        assert_eq!(HyperLogLog::from_bytes(&[1, 2, 3]).estimate(), 0);
+    }
+}
@@ -0,0 +1,541 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+use crate::auth::ApiKey;
+use crate::distributed_filter::ensure_partition_for;
+use crate::errors::AppError;
+use crate::expiry::Expiry;
+use crate::extract::{Json, Path};
+use crate::filter_sync::FilterEvent;
+use crate::metrics::{REQUEST_COUNTER, REQUEST_DURATION};
+use crate::models::{
+    BatchCreateResponse, BatchItemResult, CreateUrl, ExtendUrl, RedirectType, UpdateUrl,
+    UrlResponse,
+};
+use crate::rate_limit::consume_quota;
+use crate::redis::CachedUrl;
+use crate::AppState;
+
+#[derive(sqlx::FromRow)]
+pub struct LinkRow {
+    short_code: String,
+    long_url: String,
+    expiry_date: NaiveDate,
+    expires_at: Option<DateTime<Utc>>,
+    redirect_type: i16,
+}
+
+impl LinkRow {
+    fn expiry(&self) -> Expiry {
+        Expiry {
+            expiry_date: self.expiry_date,
+            expires_at: self.expires_at,
+        }
+    }
+
+    fn to_response(&self) -> UrlResponse {
+        UrlResponse {
+            short_code: self.short_code.clone(),
+            long_url: self.long_url.clone(),
+            expiry_date: self.expiry().display_date(),
+            expires_at: self.expires_at,
+            redirect_type: self.redirect_type as u16,
+        }
+    }
+}
+
+// Longest code `urls.short_code` (VARCHAR(50)) can hold
+const MAX_SHORT_CODE_LEN: usize = 50;
+// Top-level paths a custom code would shadow
+const RESERVED_SHORT_CODES: &[&str] = &["api", "metrics", "healthz", "readyz"];
+
+// Custom codes use the same alphabet as generated ones, so they are safe in
+// URLs and Redis keys
+pub fn validate_custom_short_code(short_code: &str) -> Result<(), AppError> {
+    let invalid = |message: &str| AppError::Validation {
+        field: "custom_short_code",
+        message: message.to_string(),
+    };
+    if short_code.is_empty() || short_code.len() > MAX_SHORT_CODE_LEN {
+        return Err(invalid("must be 1 to 50 characters long"));
+    }
+    if !short_code
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    {
+        return Err(invalid("may only contain letters, digits, '_' and '-'"));
+    }
+    if RESERVED_SHORT_CODES.contains(&short_code) {
+        return Err(invalid("is reserved"));
+    }
+    Ok(())
+}
+
+// Generated codes tried before giving up; a filter hit on a fresh nanoid is
+// almost always a false positive, so it only means drawing another one
+const GENERATE_ATTEMPTS: usize = 5;
+
+// A new 8-character code that `is_taken` doesn't reject
+pub fn generate_short_code(mut is_taken: impl FnMut(&str) -> bool) -> Option<String> {
+    (0..GENERATE_ATTEMPTS)
+        .map(|_| nanoid::nanoid!(8))
+        .find(|short_code| !is_taken(short_code))
+}
+
+enum ExpiryChange {
+    Keep,
+    Set(NaiveDate),
+    ExtendMonths(u32),
+}
+
+// Management reads go to the writer so a client sees its own changes immediately.
+// Links owned by other keys are reported as missing rather than forbidden.
+pub async fn fetch_link(
+    state: &AppState,
+    short_code: &str,
+    api_key: &ApiKey,
+) -> Result<LinkRow, AppError> {
+    sqlx::query_as(
+        "SELECT short_code, long_url, expiry_date, expires_at, redirect_type FROM urls
+         WHERE short_code = $1
+         AND owner_key_id = $2
+         AND expiry_date >= CURRENT_DATE
+         AND (expires_at IS NULL OR expires_at > now())",
+    )
+    .bind(short_code)
+    .bind(api_key.id)
+    .fetch_optional(&state.db.writer)
+    .await?
+    .ok_or(AppError::NotFound)
+}
+
+pub async fn get_url(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Path(short_code): Path<String>,
+) -> Result<Json<UrlResponse>, AppError> {
+    let link = fetch_link(&state, &short_code, &api_key).await?;
+    Ok(Json(link.to_response()))
+}
+
+pub async fn update_url(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Path(short_code): Path<String>,
+    Json(payload): Json<UpdateUrl>,
+) -> Result<Json<UrlResponse>, AppError> {
+    let expiry = match payload.expiry_date {
+        Some(date) => ExpiryChange::Set(date),
+        None => ExpiryChange::Keep,
+    };
+    let link = apply_update(
+        &state,
+        &api_key,
+        &short_code,
+        payload.long_url,
+        payload.redirect_type,
+        expiry,
+    )
+    .await?;
+    Ok(Json(link.to_response()))
+}
+
+pub async fn extend_url(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Path(short_code): Path<String>,
+    Json(payload): Json<ExtendUrl>,
+) -> Result<Json<UrlResponse>, AppError> {
+    if payload.months == 0 {
+        return Err(AppError::Validation {
+            field: "months",
+            message: "must be at least 1".to_string(),
+        });
+    }
+    let link = apply_update(
+        &state,
+        &api_key,
+        &short_code,
+        None,
+        None,
+        ExpiryChange::ExtendMonths(payload.months),
+    )
+    .await?;
+    Ok(Json(link.to_response()))
+}
+
+pub async fn delete_url(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Path(short_code): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let deleted: Vec<(NaiveDate,)> = sqlx::query_as(
+        "DELETE FROM urls WHERE short_code = $1 AND owner_key_id = $2
+         RETURNING expiry_date",
+    )
+    .bind(&short_code)
+    .bind(api_key.id)
+    .fetch_all(&state.db.writer)
+    .await?;
+
+    if deleted.is_empty() {
+        return Err(AppError::NotFound);
+    }
+
+    let events: Vec<FilterEvent> = deleted
+        .iter()
+        .map(|(expiry_date,)| FilterEvent::remove(&short_code, *expiry_date))
+        .collect();
+    {
+        let mut filter = state.distributed_filter.lock().await;
+        for (expiry_date,) in &deleted {
+            filter.remove(&short_code, *expiry_date);
+        }
+    }
+    state.filter_sync.publish(&events).await;
+    state.redis.delete_short_url(&short_code).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn apply_update(
+    state: &AppState,
+    api_key: &ApiKey,
+    short_code: &str,
+    long_url: Option<String>,
+    redirect_type: Option<u16>,
+    expiry: ExpiryChange,
+) -> Result<LinkRow, AppError> {
+    let current = fetch_link(state, short_code, api_key).await?;
+
+    let redirect_type = match redirect_type {
+        Some(status) => RedirectType::from_status(status)
+            .ok_or_else(|| AppError::Validation {
+                field: "redirect_type",
+                message: format!("unsupported redirect_type {}", status),
+            })?
+            .as_u16() as i16,
+        None => current.redirect_type,
+    };
+    let long_url = long_url.unwrap_or_else(|| current.long_url.clone());
+    let now = Utc::now();
+    let expiry = match expiry {
+        ExpiryChange::Keep => current.expiry(),
+        ExpiryChange::Set(date) => {
+            state
+                .expiry_policy
+                .check("expiry_date", Expiry::whole_day(date), now)?
+        }
+        ExpiryChange::ExtendMonths(_) if current.expiry().is_never() => {
+            return Err(AppError::Validation {
+                field: "months",
+                message: "link never expires".to_string(),
+            });
+        }
+        ExpiryChange::ExtendMonths(months) => {
+            let months = chrono::Months::new(months);
+            // Sub-day expiries keep their time of day
+            let extended = match current.expires_at {
+                Some(at) => at.checked_add_months(months).map(Expiry::exact),
+                None => current
+                    .expiry_date
+                    .checked_add_months(months)
+                    .map(Expiry::whole_day),
+            }
+            .ok_or_else(|| AppError::Validation {
+                field: "months",
+                message: "expiry date out of range".to_string(),
+            })?;
+            state.expiry_policy.check("months", extended, now)?
+        }
+    };
+    let expiry_date = expiry.expiry_date;
+
+    if expiry_date != current.expiry_date {
+        ensure_partition_for(&state.db.writer, expiry_date, state.window_months).await?;
+    }
+
+    // Changing expiry_date makes Postgres move the row into the matching partition.
+    // Matching on the old expiry guards against a concurrent update.
+    let updated: LinkRow = sqlx::query_as(
+        "UPDATE urls SET long_url = $3, redirect_type = $4, expiry_date = $5, expires_at = $7
+         WHERE short_code = $1 AND expiry_date = $2 AND owner_key_id = $6
+         RETURNING short_code, long_url, expiry_date, expires_at, redirect_type",
+    )
+    .bind(short_code)
+    .bind(current.expiry_date)
+    .bind(&long_url)
+    .bind(redirect_type)
+    .bind(expiry_date)
+    .bind(api_key.id)
+    .bind(expiry.expires_at)
+    .fetch_optional(&state.db.writer)
+    .await?
+    .ok_or(AppError::NotFound)?;
+
+    if updated.expiry_date != current.expiry_date {
+        {
+            let mut filter = state.distributed_filter.lock().await;
+            filter.remove(short_code, current.expiry_date);
+            filter.insert(short_code, updated.expiry_date)?;
+        }
+        state
+            .filter_sync
+            .publish(&[
+                FilterEvent::remove(short_code, current.expiry_date),
+                FilterEvent::insert(short_code, updated.expiry_date),
+            ])
+            .await;
+    }
+
+    state
+        .redis
+        .set_short_url(
+            short_code,
+            &CachedUrl {
+                long_url: updated.long_url.clone(),
+                expiry_date: updated.expiry_date,
+                expires_at: updated.expires_at,
+                redirect_type: Some(updated.redirect_type as u16),
+                flags: 0,
+            },
+        )
+        .await?;
+
+    Ok(updated)
+}
+
+// A batch item that passed validation and is waiting to be inserted
+struct PendingLink {
+    index: usize,
+    // Empty for generated codes until they are drawn against the filter
+    short_code: String,
+    long_url: String,
+    expiry: Expiry,
+    redirect_type: RedirectType,
+}
+
+pub async fn create_urls_batch(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Json(payload): Json<Vec<CreateUrl>>,
+) -> Result<(HeaderMap, Json<BatchCreateResponse>), AppError> {
+    let start_metric = tokio::time::Instant::now();
+    REQUEST_COUNTER.inc();
+
+    if payload.is_empty() {
+        return Err(AppError::BadRequest("batch is empty".to_string()));
+    }
+    if payload.len() > state.max_batch_size {
+        return Err(AppError::BadRequest(format!(
+            "batch of {} exceeds the limit of {}",
+            payload.len(),
+            state.max_batch_size
+        )));
+    }
+
+    // The whole batch counts against the quota up front
+    let quota_headers = consume_quota(&state, &api_key, payload.len() as u64).await?;
+
+    let now = Utc::now();
+    let today = now.date_naive();
+    let mut results: Vec<Option<BatchItemResult>> = (0..payload.len()).map(|_| None).collect();
+    let mut pending = Vec::with_capacity(payload.len());
+    let mut seen = HashSet::new();
+
+    for (index, item) in payload.into_iter().enumerate() {
+        let validated = (|| -> Result<PendingLink, String> {
+            let redirect_type = match item.redirect_type {
+                Some(status) => RedirectType::from_status(status)
+                    .ok_or_else(|| format!("unsupported redirect_type {}", status))?,
+                None => state.default_redirect,
+            };
+            let expiry = state
+                .expiry_policy
+                .resolve(&item, now)
+                .map_err(|e| e.to_string())?;
+            if let Some(custom) = &item.custom_short_code {
+                validate_custom_short_code(custom).map_err(|e| e.to_string())?;
+            }
+            let short_code = item.custom_short_code.unwrap_or_default();
+            if !short_code.is_empty() && !seen.insert(short_code.clone()) {
+                return Err(format!("short code {} repeated in batch", short_code));
+            }
+            Ok(PendingLink {
+                index,
+                short_code,
+                long_url: item.long_url,
+                expiry,
+                redirect_type,
+            })
+        })();
+
+        match validated {
+            Ok(link) => pending.push(link),
+            Err(error) => results[index] = Some(batch_error(index, error)),
+        }
+    }
+
+    // One lock acquisition for every existence check
+    {
+        let filter = state.distributed_filter.lock().await;
+        pending.retain_mut(|link| {
+            if link.short_code.is_empty() {
+                let generated = generate_short_code(|short_code| {
+                    seen.contains(short_code) || filter.contains(short_code, today)
+                });
+                match generated {
+                    Some(short_code) => {
+                        seen.insert(short_code.clone());
+                        link.short_code = short_code;
+                        true
+                    }
+                    None => {
+                        results[link.index] =
+                            Some(batch_error(link.index, "no free short code found"));
+                        false
+                    }
+                }
+            } else if filter.contains(&link.short_code, today) {
+                results[link.index] = Some(batch_error(link.index, "short code already exists"));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    let months: BTreeSet<NaiveDate> = pending.iter().map(|link| link.expiry.expiry_date).collect();
+    for expiry_date in months {
+        ensure_partition_for(&state.db.writer, expiry_date, state.window_months).await?;
+    }
+
+    // Single multi-row insert. Codes that already have a live row are skipped
+    // rather than failing the batch; the primary key includes expiry_date, so
+    // ON CONFLICT alone would only catch the same code with the same expiry.
+    let inserted: HashSet<String> = if pending.is_empty() {
+        HashSet::new()
+    } else {
+        let codes: Vec<&str> = pending.iter().map(|l| l.short_code.as_str()).collect();
+        let long_urls: Vec<&str> = pending.iter().map(|l| l.long_url.as_str()).collect();
+        let expiry_dates: Vec<NaiveDate> = pending.iter().map(|l| l.expiry.expiry_date).collect();
+        let expires_at: Vec<Option<DateTime<Utc>>> =
+            pending.iter().map(|l| l.expiry.expires_at).collect();
+        let redirect_types: Vec<i16> = pending
+            .iter()
+            .map(|l| l.redirect_type.as_u16() as i16)
+            .collect();
+
+        sqlx::query_scalar::<_, String>(
+            "INSERT INTO urls (short_code, long_url, expiry_date, redirect_type, expires_at, owner_key_id)
+             SELECT t.*, $6::bigint FROM UNNEST(
+                 $1::varchar[], $2::text[], $3::date[], $4::smallint[], $5::timestamptz[]
+             ) AS t(short_code, long_url, expiry_date, redirect_type, expires_at)
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM urls u
+                 WHERE u.short_code = t.short_code AND u.expiry_date >= CURRENT_DATE
+                 AND (u.expires_at IS NULL OR u.expires_at > now())
+             )
+             ON CONFLICT DO NOTHING
+             RETURNING short_code",
+        )
+        .bind(&codes)
+        .bind(&long_urls)
+        .bind(&expiry_dates)
+        .bind(&redirect_types)
+        .bind(&expires_at)
+        .bind(api_key.id)
+        .fetch_all(&state.db.writer)
+        .await?
+        .into_iter()
+        .collect()
+    };
+
+    pending.retain(|link| {
+        if inserted.contains(&link.short_code) {
+            true
+        } else {
+            results[link.index] = Some(batch_error(link.index, "short code already exists"));
+            false
+        }
+    });
+
+    {
+        let mut filter = state.distributed_filter.lock().await;
+        for link in &pending {
+            if let Err(e) = filter.insert(&link.short_code, link.expiry.expiry_date) {
+                tracing::error!("Filter insert failed for {}: {}", link.short_code, e);
+            }
+        }
+    }
+    let events: Vec<FilterEvent> = pending
+        .iter()
+        .map(|link| FilterEvent::insert(&link.short_code, link.expiry.expiry_date))
+        .collect();
+    state.filter_sync.publish(&events).await;
+
+    let cache_entries: Vec<(String, CachedUrl)> = pending
+        .iter()
+        .map(|link| {
+            (
+                link.short_code.clone(),
+                CachedUrl {
+                    long_url: link.long_url.clone(),
+                    expiry_date: link.expiry.expiry_date,
+                    expires_at: link.expiry.expires_at,
+                    redirect_type: Some(link.redirect_type.as_u16()),
+                    flags: 0,
+                },
+            )
+        })
+        .collect();
+    // The rows are committed, so a cache failure only costs a database lookup later
+    if let Err(e) = state.redis.set_short_urls(&cache_entries).await {
+        tracing::error!("Failed to cache batch: {}", e);
+    }
+
+    let created = pending.len();
+    for link in pending {
+        results[link.index] = Some(BatchItemResult {
+            index: link.index,
+            status: "created",
+            url: Some(UrlResponse {
+                short_code: link.short_code,
+                long_url: link.long_url,
+                expiry_date: link.expiry.display_date(),
+                expires_at: link.expiry.expires_at,
+                redirect_type: link.redirect_type.as_u16(),
+            }),
+            error: None,
+        });
+    }
+
+    let results: Vec<BatchItemResult> = results.into_iter().flatten().collect();
+
+    REQUEST_DURATION
+        .with_label_values(&["create_url_batch"])
+        .observe(start_metric.elapsed().as_secs_f64());
+
+    Ok((
+        quota_headers,
+        Json(BatchCreateResponse {
+            created,
+            failed: results.len() - created,
+            results,
+        }),
+    ))
+}
+
+fn batch_error(index: usize, error: impl ToString) -> BatchItemResult {
+    BatchItemResult {
+        index,
+        status: "error",
+        url: None,
+        error: Some(error.to_string()),
+    }
+}
@@ -1,159 +1,255 @@
-use aws::persistance::initialize_distributed_filter_system;
+use analytics::{ClickRecorder, ClickWriter, GeoIpDb};
+use auth::ApiKey;
+use aws::persistance::{
+    initialize_distributed_filter_system, run_distributed_snapshot_service,
+    DistributedFilterPersistence,
+};
 use axum::{
-    extract::{Path, State},
-    response::{IntoResponse, Redirect, Response},
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    middleware,
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
+    Router,
 };
-use distributed_filter::DistributedFilter;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{Config, FilterTrust};
+use db::Database;
+use distributed_filter::{ensure_partition_for, DistributedFilter};
 use errors::AppError;
-use metrics::{CPU_USAGE, MEMORY_USAGE, REQUEST_COUNTER, REQUEST_DURATION};
-use models::{CreateUrl, UrlResponse};
+use expiry::ExpiryPolicy;
+use extract::{Json, Path};
+use filter_audit::AuditSettings;
+use filter_sync::{FilterEvent, FilterSync};
+use metrics::{
+    CPU_USAGE, FILTER_FALSE_NEGATIVES, FILTER_FALSE_POSITIVES, MEMORY_USAGE, NEGATIVE_CACHE_HITS,
+    REQUEST_COUNTER, REQUEST_DURATION,
+};
+use models::{CreateUrl, RedirectType, UrlResponse};
 use prometheus::{Encoder, TextEncoder};
-use redis::RedisManager;
-use sqlx::{migrate::Migrator, PgPool};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::Mutex;
-use tower_governor::{
-    governor::GovernorConfigBuilder, key_extractor::PeerIpKeyExtractor, GovernorLayer,
+use rate_limit::{
+    consume_quota, ApiRateLimiter, ClientIpKeyExtractor, DistributedRateLimitLayer, TrustedProxies,
 };
+use redis::{CachedUrl, RedisManager};
+use shutdown::{shutdown_signal, Shutdown};
+use std::{
+    future::IntoFuture,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Mutex;
+use tower_governor::{governor::GovernorConfigBuilder, GovernorLayer};
 use tower_http::trace::TraceLayer;
+mod analytics;
+mod auth;
 mod aws;
+mod change_listener;
+mod cli;
+mod commands;
+mod config;
 mod cron;
+mod db;
 mod distributed_filter;
 mod errors;
+mod expiry;
+mod extract;
+mod filter_audit;
+mod filter_sync;
+mod health;
+mod hll;
+mod links;
 mod metrics;
 mod models;
+mod rate_limit;
 mod redis;
+mod request_id;
+mod shutdown;
+mod stats;
+
+// How long background tasks get to finish their current run once requests
+// have drained, e.g. the click writer's last flush
+const BACKGROUND_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 struct AppState {
-    pool: PgPool,
+    db: Database,
     distributed_filter: Arc<Mutex<DistributedFilter>>,
     redis: RedisManager,
+    default_redirect: RedirectType,
+    max_batch_size: usize,
+    expiry_policy: ExpiryPolicy,
+    // Expiry dates this many months out or more go to the future partition
+    window_months: u32,
+    api_limiter: Arc<ApiRateLimiter>,
+    trusted_proxies: Arc<TrustedProxies>,
+    clicks: ClickRecorder,
+    filter_sync: FilterSync,
+    filter_trust: FilterTrust,
+    audit: AuditSettings,
+    // Set once the distributed filter has been loaded or rebuilt
+    filter_ready: Arc<AtomicBool>,
 }
 
 async fn create_short_url(
     State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
     Json(payload): Json<CreateUrl>,
-) -> Result<Json<UrlResponse>, AppError> {
+) -> Result<(HeaderMap, Json<UrlResponse>), AppError> {
     // metrics
     let start_metric = tokio::time::Instant::now();
     REQUEST_COUNTER.inc();
     //
 
-    let short_code = match payload.custom_short_code {
-        Some(custom) => custom,
-        None => nanoid::nanoid!(8),
+    let quota_headers = consume_quota(&state, &api_key, 1).await?;
+    let response = create_link(&state, payload, Some(api_key.id)).await?;
+
+    // metrics:
+    let duration = start_metric.elapsed().as_secs_f64();
+    REQUEST_DURATION
+        .with_label_values(&["create_url"])
+        .observe(duration);
+    //q
+
+    Ok((quota_headers, Json(response)))
+}
+
+// Shared by the HTTP handler and the `create` subcommand
+async fn create_link(
+    state: &AppState,
+    payload: CreateUrl,
+    owner_key_id: Option<i64>,
+) -> Result<UrlResponse, AppError> {
+    let redirect_type = match payload.redirect_type {
+        Some(status) => RedirectType::from_status(status).ok_or_else(|| AppError::Validation {
+            field: "redirect_type",
+            message: format!("unsupported redirect_type {}", status),
+        })?,
+        None => state.default_redirect,
     };
-    let current_date = chrono::Utc::now();
-    let expiry_date_str = current_date
-        .checked_add_months(chrono::Months::new(payload.months_valid.unwrap_or(1)))
-        .unwrap()
-        .format("%Y-%m-%d")
-        .to_string();
-    let format = time::macros::format_description!("[year]-[month]-[day]");
-
-    let expiry_date = sqlx::types::time::Date::parse(&expiry_date_str, format).unwrap();
-
-    let expiry_date_str2 = current_date
-        .checked_add_months(chrono::Months::new(payload.months_valid.unwrap_or(1) + 1))
-        .unwrap()
-        .format("%Y-%m-%d")
-        .to_string();
-
-    let end_month = sqlx::types::time::Date::parse(&expiry_date_str2, format)
-        .unwrap()
-        .replace_day(1)
-        .unwrap();
-
-    let partition_name = format!(
-        "urls_y{}m{:02}",
-        expiry_date.year(),
-        current_date
-            .date_naive()
-            .checked_add_months(chrono::Months::new(payload.months_valid.unwrap_or(1)))
-            .unwrap()
-            .format("%m")
-    );
 
-    println!("Partition name: {}", partition_name);
-    println!("End date: {}", end_month);
-    println!("{}-{}-01", expiry_date.year(), expiry_date.month());
-
-    let query = format!(
-        "CREATE TABLE IF NOT EXISTS {} 
-         PARTITION OF urls 
-         FOR VALUES FROM ('{}') TO ('{}');",
-        partition_name,
-        format!("{}-{}-01", expiry_date.year(), expiry_date.month()),
-        end_month
-    );
+    let now = chrono::Utc::now();
+    let expiry = state.expiry_policy.resolve(&payload, now)?;
+    let expiry_date = expiry.expiry_date;
+    let current_date = now.date_naive();
 
-    sqlx::query(&query).execute(&state.pool).await?;
+    ensure_partition_for(&state.db.writer, expiry_date, state.window_months).await?;
 
-    if state
-        .distributed_filter
-        .lock()
-        .await
-        .contains(&short_code, current_date.date_naive())
-    {
-        return Err(AppError::NotFound);
-    }
-    /////////////////////////
-    state
-        .redis
-        .set_short_url(&short_code, &payload.long_url)
-        .await?;
+    let short_code = match payload.custom_short_code {
+        Some(custom) => {
+            links::validate_custom_short_code(&custom)?;
+            let in_filter = state
+                .distributed_filter
+                .lock()
+                .await
+                .contains(&custom, current_date);
+            // The primary key includes expiry_date, so it won't stop a custom code
+            // being reused with another expiry, and the filter may be empty (the
+            // `create` command) or missing entries
+            if in_filter || code_is_live(&state.db.writer, &custom).await? {
+                return Err(AppError::Conflict(format!(
+                    "short code {} is already taken",
+                    custom
+                )));
+            }
+            custom
+        }
+        // The caller never chose this code, so a filter hit means drawing another
+        None => {
+            let filter = state.distributed_filter.lock().await;
+            links::generate_short_code(|short_code| filter.contains(short_code, current_date))
+                .ok_or_else(|| AppError::Internal("no free short code found".to_string()))?
+        }
+    };
 
-    ///////////////////////
     sqlx::query!(
-        "INSERT INTO urls (short_code, long_url, expiry_date)
-    VALUES ($1, $2, $3::date)",
+        "INSERT INTO urls (short_code, long_url, expiry_date, expires_at, redirect_type, owner_key_id)
+    VALUES ($1, $2, $3::date, $4, $5, $6)",
         short_code,
         payload.long_url,
-        expiry_date,
+        expiry_date as chrono::NaiveDate,
+        expiry.expires_at as Option<chrono::DateTime<chrono::Utc>>,
+        redirect_type.as_u16() as i16,
+        owner_key_id,
     )
-    .execute(&state.pool)
+    .execute(&state.db.writer)
     .await?;
 
-    let insertion = state.distributed_filter.lock().await.insert(
-        &short_code,
-        current_date
-            .checked_add_months(chrono::Months::new(payload.months_valid.unwrap_or(1)))
-            .unwrap()
-            .date_naive(),
-    );
-
-    println!("Insertion: {:#?}", insertion);
+    // Cached only once the row is committed, so a failed insert can't leave a
+    // link behind that redirects read from Redis; a cache failure only costs a
+    // database lookup later
+    if let Err(e) = state
+        .redis
+        .set_short_url(
+            &short_code,
+            &CachedUrl {
+                long_url: payload.long_url.clone(),
+                expiry_date,
+                expires_at: expiry.expires_at,
+                redirect_type: Some(redirect_type.as_u16()),
+                flags: 0,
+            },
+        )
+        .await
+    {
+        tracing::error!("Failed to cache {}: {}", short_code, e);
+    }
 
-    // metrics:
-    let duration = start_metric.elapsed().as_secs_f64();
-    REQUEST_DURATION
-        .with_label_values(&["create_url"])
-        .observe(duration);
-    //q
+    state
+        .distributed_filter
+        .lock()
+        .await
+        .insert(&short_code, expiry_date)?;
+    state
+        .filter_sync
+        .publish(&[FilterEvent::insert(&short_code, expiry_date)])
+        .await;
 
-    Ok(Json(UrlResponse {
+    Ok(UrlResponse {
         short_code,
         long_url: payload.long_url,
-        expiry_date: expiry_date.to_string(),
-    }))
+        expiry_date: expiry.display_date(),
+        expires_at: expiry.expires_at,
+        redirect_type: redirect_type.as_u16(),
+    })
+}
+
+async fn code_is_live(pool: &sqlx::PgPool, short_code: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM urls
+         WHERE short_code = $1 AND expiry_date >= CURRENT_DATE
+         AND (expires_at IS NULL OR expires_at > now()))",
+    )
+    .bind(short_code)
+    .fetch_one(pool)
+    .await
 }
 
 async fn redirect_to_long_url(
     State(state): State<Arc<AppState>>,
     Path(short_code): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
     // metric
     let start = tokio::time::Instant::now();
     REQUEST_COUNTER.inc();
     //
-    if !state
-        .distributed_filter
-        .lock()
-        .await
-        .contains(&short_code, chrono::Utc::now().date_naive())
-    {
+    let in_filter = match state.filter_trust {
+        FilterTrust::Disabled => None,
+        _ => Some(
+            state
+                .distributed_filter
+                .lock()
+                .await
+                .contains(&short_code, chrono::Utc::now().date_naive()),
+        ),
+    };
+    if in_filter == Some(false) && state.filter_trust == FilterTrust::Strict {
         println!("Short code not found in filter");
         return AppError::NotFound.into_response();
     }
@@ -163,140 +259,441 @@ async fn redirect_to_long_url(
         .with_label_values(&["redirect"])
         .observe(duration);
     //
-    match state.redis.get_long_url(&short_code).await {
-        Ok(Some(long_url)) => {
+    // A cache outage shouldn't take redirects down with it; fall back to the database
+    let cached = match state.redis.get_short_url(&short_code).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            tracing::error!("Redis lookup for {} failed: {}", short_code, e);
+            None
+        }
+    };
+    match cached {
+        Some(cached) => {
             println!("Got it from redis");
-            Redirect::permanent(&long_url).into_response()
+            if !cached.expiry().is_live(chrono::Utc::now()) {
+                return AppError::NotFound.into_response();
+            }
+            if in_filter == Some(false) {
+                repair_filter(&state, &short_code, cached.expiry_date).await;
+            }
+            let client_ip = state.trusted_proxies.client_ip(peer.ip(), &headers);
+            state.clicks.record(&short_code, client_ip, &headers);
+            cached
+                .redirect_type
+                .and_then(RedirectType::from_status)
+                .unwrap_or(state.default_redirect)
+                .redirect(&cached.long_url)
         }
-        Ok(None) => {
-            match sqlx::query!(
-                "SELECT long_url FROM urls
-                 WHERE short_code = $1
-                 AND expiry_date >= CURRENT_DATE",
-                short_code
-            )
-            .fetch_optional(&state.pool)
-            .await
-            {
-                Ok(Some(url)) => Redirect::permanent(&url.long_url).into_response(),
-                _ => AppError::NotFound.into_response(),
+        None => {
+            if let Ok(true) = state.redis.is_negative(&short_code).await {
+                NEGATIVE_CACHE_HITS.inc();
+                return AppError::NotFound.into_response();
+            }
+            match state.db.find_live_url(&short_code).await {
+                Ok(Some(url)) => {
+                    if in_filter == Some(false) {
+                        repair_filter(&state, &short_code, url.expiry_date).await;
+                    }
+                    let client_ip = state.trusted_proxies.client_ip(peer.ip(), &headers);
+                    state.clicks.record(&short_code, client_ip, &headers);
+                    RedirectType::from_status(url.redirect_type as u16)
+                        .unwrap_or(state.default_redirect)
+                        .redirect(&url.long_url)
+                }
+                Ok(None) => {
+                    // The filter said yes but the database says no
+                    if in_filter == Some(true) {
+                        FILTER_FALSE_POSITIVES.inc();
+                    }
+                    if let Err(e) = state.redis.set_negative(&short_code).await {
+                        tracing::warn!("Failed to cache miss for {}: {}", short_code, e);
+                    }
+                    AppError::NotFound.into_response()
+                }
+                Err(e) => {
+                    tracing::error!("Lookup for {} failed: {}", short_code, e);
+                    AppError::Unavailable("database").into_response()
+                }
             }
         }
-        Err(_) => AppError::NotFound.into_response(),
     }
 }
+// A live link the filter missed while filter.trust is advisory: count it and
+// put it back so later redirects don't need the lookup
+async fn repair_filter(state: &AppState, short_code: &str, expiry_date: chrono::NaiveDate) {
+    FILTER_FALSE_NEGATIVES.inc();
+    tracing::warn!("Filter missed live link {}, adding it back", short_code);
+    if let Err(e) = state
+        .distributed_filter
+        .lock()
+        .await
+        .insert(short_code, expiry_date)
+    {
+        tracing::error!("Failed to repair filter entry for {}: {}", short_code, e);
+        return;
+    }
+    state
+        .filter_sync
+        .publish(&[FilterEvent::insert(short_code, expiry_date)])
+        .await;
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let pool = PgPool::connect(&database_url).await?;
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml());
+        return Ok(());
+    }
 
-    // Run migrations
-    // sqlx::migrate!("./migrations").run(&pool).await?;
-    let migrator = Migrator::new(std::path::Path::new("./migrations")).await?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Migrate => commands::migrate(&config).await,
+        Command::Snapshot { action } => commands::snapshot(&config, action).await,
+        Command::Partitions { action } => commands::partitions(&config, action).await,
+        Command::Filter { action } => commands::filter(&config, action).await,
+        Command::Create(args) => commands::create(&config, args).await,
+        Command::Keys { action } => commands::keys(&config, action).await,
+    }
+}
 
-    // Run migrations
-    migrator.run(&pool).await?;
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::connect(&config.database).await?;
+    let pool = db.writer.clone();
+    commands::run_migrations(&pool).await?;
 
     // Initialize distributed filter system
-    let distributed_filter = initialize_distributed_filter_system(
-        &pool,
-        "affinitys3".to_string(),
-        "distributed-filter-backups".to_string(),
-    )
-    .await?;
+    let distributed_filter = initialize_distributed_filter_system(&config.filter)?;
+
+    let persistence = Arc::new(
+        DistributedFilterPersistence::new(
+            config.snapshot.bucket.clone(),
+            config.snapshot.prefix.clone(),
+        )
+        .await?,
+    );
 
-    let redis_manager = redis::RedisManager::new(&redis_url).await?;
+    let redis_manager = redis::RedisManager::new(&config.redis).await?;
+
+    let filter_sync = if config.filter.sync {
+        FilterSync::new(redis_manager.clone(), config.filter.sync_max_events)
+    } else {
+        FilterSync::disabled()
+    };
+
+    // Limits are shared through Redis only when enabled; otherwise each instance counts alone
+    let shared_limits = config.rate_limit.distributed.then(|| redis_manager.clone());
+
+    let trusted_proxies = Arc::new(
+        TrustedProxies::parse(&config.rate_limit.trusted_proxies)
+            .expect("trusted_proxies is checked when the config is validated"),
+    );
+
+    let (clicks, click_queue) = if config.analytics.enabled {
+        let (recorder, queue) = ClickRecorder::new(
+            config.analytics.queue_capacity,
+            config.analytics.live_counters,
+        );
+        (recorder, Some(queue))
+    } else {
+        (ClickRecorder::disabled(), None)
+    };
 
     let app_state = Arc::new(AppState {
-        pool: pool.clone(),
+        db: db.clone(),
         distributed_filter: distributed_filter.clone(),
         redis: redis_manager,
+        default_redirect: config.default_redirect(),
+        max_batch_size: config.links.max_batch_size,
+        expiry_policy: ExpiryPolicy::new(&config.links),
+        window_months: config.filter.window_months,
+        api_limiter: Arc::new(ApiRateLimiter::new(
+            &config.rate_limit.tiers,
+            shared_limits.clone(),
+        )),
+        trusted_proxies: trusted_proxies.clone(),
+        clicks,
+        filter_sync: filter_sync.clone(),
+        filter_trust: config.filter.trust,
+        audit: AuditSettings::new(&config.filter),
+        filter_ready: Arc::new(AtomicBool::new(false)),
     });
 
+    let (shutdown_tx, shutdown) = Shutdown::new();
+    let mut background_tasks = Vec::new();
+
     // Schedule cleanup task
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(chrono::Duration::days(30).to_std().unwrap());
+    // Validated to 1..=365 days, so this can't overflow
+    let cleanup_interval = Duration::from_secs(config.cleanup.interval_days as u64 * 86_400);
+    let mut cleanup_shutdown = shutdown.clone();
+    let cleanup_pool = pool.clone();
+    background_tasks.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cleanup_interval);
         loop {
-            interval.tick().await;
-            if let Err(e) = cron::cleanup_expired_partitions(&pool).await {
-                eprintln!("Cleanup error: {}", e);
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cleanup_shutdown.wait() => break,
             }
-
-            if let Err(e) = distributed_filter
-                .lock()
-                .await
-                .cleanup_expired_partitions(&pool)
-                .await
+            if let Err(e) =
+                cron::cleanup_expired_partitions(&cleanup_pool, chrono::Utc::now().date_naive())
+                    .await
             {
                 eprintln!("Cleanup error: {}", e);
             }
         }
-    });
+    }));
+
+    if let Some(click_queue) = click_queue {
+        let geoip = if config.analytics.geoip_csv.is_empty() {
+            None
+        } else {
+            let geoip = GeoIpDb::load(&config.analytics.geoip_csv)?;
+            tracing::info!("Loaded {} GeoIP ranges", geoip.len());
+            Some(geoip)
+        };
+        background_tasks.push(tokio::spawn(analytics::run_click_writer(
+            click_queue,
+            ClickWriter::new(
+                db.writer.clone(),
+                geoip,
+                analytics::visitor_salt(&db.writer, &config.analytics.visitor_salt).await?,
+                config
+                    .analytics
+                    .live_counters
+                    .then(|| app_state.redis.clone()),
+            ),
+            config.analytics.batch_size,
+            Duration::from_millis(config.analytics.flush_interval_ms),
+            shutdown.clone(),
+        )));
+    }
 
-    // tokio::spawn(async move {
-    //     run_distributed_snapshot_service(distributed_filter, filter_persistence)
-    //         .await;
-    // });
+    if config.analytics.enabled && config.analytics.live_counters {
+        background_tasks.push(tokio::spawn(analytics::run_counter_flush(
+            app_state.redis.clone(),
+            db.writer.clone(),
+            Duration::from_secs(config.analytics.counter_flush_interval_secs),
+            shutdown.clone(),
+        )));
+    }
 
-    tokio::spawn(collect_system_metrics());
-    let write_limit = Arc::new(
-        GovernorConfigBuilder::default()
-            .per_second(5) // 5 writes per second
-            .burst_size(10) // Allow bursts up to 10
-            .use_headers()
-            .key_extractor(PeerIpKeyExtractor)
-            .finish()
-            .unwrap(),
-    );
+    if config.analytics.enabled {
+        background_tasks.push(tokio::spawn(analytics::run_click_rollups(
+            db.writer.clone(),
+            Duration::from_secs(config.analytics.rollup_interval_secs),
+            Duration::from_secs(config.analytics.rollup_delay_secs),
+            shutdown.clone(),
+        )));
+    }
+
+    background_tasks.push(tokio::spawn(collect_system_metrics(shutdown.clone())));
+    background_tasks.push(tokio::spawn(db.clone().monitor_replica(
+        Duration::from_secs(config.database.max_replica_lag_secs),
+        Duration::from_secs(config.database.replica_check_interval_secs),
+        shutdown.clone(),
+    )));
+
+    // Governor takes the interval between refills, not a rate
+    let read_period = Duration::from_secs(1) / config.rate_limit.read_per_second;
     let read_limit = Arc::new(
         GovernorConfigBuilder::default()
-            .per_second(20) // 20 reads per second
-            .burst_size(50) // Allow bursts up to 50
+            .period(read_period)
+            .burst_size(config.rate_limit.read_burst)
             .use_headers()
-            .key_extractor(PeerIpKeyExtractor)
+            .key_extractor(ClientIpKeyExtractor {
+                trusted_proxies: trusted_proxies.clone(),
+            })
+            .error_handler(rate_limit::governor_error_response)
             .finish()
             .unwrap(),
     );
 
-    let write_limit_limiter = write_limit.limiter().clone();
+    let api_limiter = app_state.api_limiter.clone();
     let read_limit_limiter = read_limit.limiter().clone();
 
-    tokio::spawn(async move {
+    let mut limiter_shutdown = shutdown.clone();
+    background_tasks.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
         loop {
-            std::thread::sleep(Duration::from_secs(60));
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = limiter_shutdown.wait() => break,
+            }
             // println!("rate limiting storage size: {}", governor_limiter.len());
-            write_limit_limiter.retain_recent();
+            api_limiter.retain_recent();
             read_limit_limiter.retain_recent();
         }
-    });
+    }));
 
-    let app = Router::new()
-        .route(
-            "/api/urls",
-            post(create_short_url).layer(GovernorLayer {
-                config: write_limit,
-            }),
-        )
+    // Every /api route is authenticated and limited per API key
+    let api = Router::new()
+        .route("/api/urls", post(create_short_url))
+        .route("/api/urls/batch", post(links::create_urls_batch))
         .route(
-            "/{short_code}",
-            get(redirect_to_long_url).layer(GovernorLayer { config: read_limit }),
+            "/api/urls/{short_code}",
+            get(links::get_url)
+                .patch(links::update_url)
+                .delete(links::delete_url),
         )
+        .route("/api/urls/{short_code}/extend", post(links::extend_url))
+        .route("/api/urls/{short_code}/stats", get(stats::get_stats))
+        .route("/api/admin/filter/audit", post(filter_audit::audit))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::api_rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            health::require_filter,
+        ));
+
+    let redirect = match shared_limits {
+        Some(redis) => get(redirect_to_long_url).layer(DistributedRateLimitLayer {
+            redis,
+            key_extractor: ClientIpKeyExtractor { trusted_proxies },
+            scope: "redirect",
+            period: read_period,
+            burst: config.rate_limit.read_burst,
+            fallback: read_limit.limiter().clone(),
+        }),
+        None => get(redirect_to_long_url).layer(GovernorLayer { config: read_limit }),
+    }
+    .layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        health::require_filter,
+    ));
+
+    let app = Router::new()
+        .merge(api)
+        .route("/{short_code}", redirect)
         .route("/metrics", get(metrics_handler2))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .layer(TraceLayer::new_for_http())
-        .with_state(app_state);
+        .layer(middleware::from_fn(request_id::request_id))
+        .with_state(app_state.clone());
+
+    let listener = tokio::net::TcpListener::bind(&config.server.bind_addr).await?;
+    println!("Server running on http://{}", config.server.bind_addr);
+    // Separate from the background tasks' signal: they keep running, e.g.
+    // writing clicks, while in-flight requests drain
+    let (server_shutdown_tx, mut server_shutdown) = Shutdown::new();
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move { server_shutdown.wait().await })
+        .into_future(),
+    );
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await?;
-    println!("Server running on http://localhost:3001");
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    // Serving starts before the filter is loaded so /readyz can report the load;
+    // requests that need the filter get 503 until it is ready
+    let feed_position = filter_sync::load_filter(
+        &distributed_filter,
+        &persistence,
+        &pool,
+        &app_state.filter_sync,
+        &config.filter,
     )
     .await?;
+    app_state.filter_ready.store(true, Ordering::Release);
+
+    background_tasks.push(tokio::spawn(filter_sync::run_filter_sync(
+        distributed_filter.clone(),
+        app_state.filter_sync.clone(),
+        feed_position,
+        shutdown.clone(),
+    )));
+
+    if config.database.listen_for_changes {
+        background_tasks.push(tokio::spawn(change_listener::run_change_listener(
+            db.writer.clone(),
+            distributed_filter.clone(),
+            app_state.redis.clone(),
+            shutdown.clone(),
+        )));
+    }
+
+    background_tasks.push(tokio::spawn(cron::run_partition_maintenance(
+        distributed_filter.clone(),
+        db.writer.clone(),
+        Duration::from_secs(config.filter.maintenance_interval_secs),
+        shutdown.clone(),
+    )));
+
+    if config.filter.audit_interval_secs > 0 {
+        background_tasks.push(tokio::spawn(filter_audit::run_filter_audit(
+            distributed_filter.clone(),
+            db.writer.clone(),
+            app_state.filter_sync.clone(),
+            app_state.audit,
+            Duration::from_secs(config.filter.audit_interval_secs),
+            shutdown.clone(),
+        )));
+    }
+
+    background_tasks.push(tokio::spawn(run_distributed_snapshot_service(
+        distributed_filter.clone(),
+        persistence.clone(),
+        Duration::from_secs(config.snapshot.interval_secs),
+        shutdown.clone(),
+    )));
+
+    tokio::select! {
+        // The server stopped on its own; the background tasks still stop below
+        result = &mut server => result??,
+        _ = shutdown_signal() => {
+            // Stop accepting connections and let in-flight requests finish
+            let _ = server_shutdown_tx.send(true);
+            let drain_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+            match tokio::time::timeout(drain_timeout, &mut server).await {
+                Ok(result) => result??,
+                Err(_) => {
+                    tracing::warn!("Requests still running after {:?}, aborting", drain_timeout);
+                    server.abort();
+                }
+            }
+        }
+    }
+
+    // Requests are done, so nothing feeds the background tasks any more
+    let _ = shutdown_tx.send(true);
+    let deadline = tokio::time::Instant::now() + BACKGROUND_STOP_TIMEOUT;
+    let mut unfinished = 0;
+    for mut task in background_tasks {
+        if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+            task.abort();
+            unfinished += 1;
+        }
+    }
+    if unfinished > 0 {
+        tracing::warn!(
+            "{} background tasks still running after {:?}, aborted",
+            unfinished,
+            BACKGROUND_STOP_TIMEOUT
+        );
+    }
+
+    // Persist every insert since the last periodic snapshot
+    match persistence
+        .save_snapshot(&*distributed_filter.lock().await)
+        .await
+    {
+        Ok(key) => tracing::info!("Saved final distributed filter snapshot: {}", key),
+        Err(e) => tracing::error!("Failed to save final distributed filter snapshot: {}", e),
+    }
 
     Ok(())
 }
@@ -318,10 +715,13 @@ async fn metrics_handler2() -> impl axum::response::IntoResponse {
     )
 }
 // System metrics collection task
-async fn collect_system_metrics() {
+async fn collect_system_metrics(mut shutdown: Shutdown) {
     let mut interval = tokio::time::interval(std::time::Duration::from_secs(15));
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
 
         // Update CPU usage
         if let Ok(cpu) = sys_info::loadavg() {
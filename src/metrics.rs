@@ -1,6 +1,7 @@
 use lazy_static::lazy_static;
 use prometheus::{
-    register_counter, register_gauge, register_histogram_vec, Counter, Gauge, HistogramVec,
+    register_counter, register_gauge, register_gauge_vec, register_histogram_vec, Counter, Gauge,
+    GaugeVec, HistogramVec,
 };
 
 lazy_static! {
@@ -26,12 +27,125 @@ lazy_static! {
         "CPU usage percentage"
     ).unwrap();
 
+    // Short codes the filter accepted but the database did not have
+    pub static ref FILTER_FALSE_POSITIVES: Counter = register_counter!(
+        "filter_false_positives_total",
+        "Filter hits confirmed missing by the database"
+    ).unwrap();
+
+    // Live links the filter missed, found because filter.trust is advisory
+    pub static ref FILTER_FALSE_NEGATIVES: Counter = register_counter!(
+        "filter_false_negative_total",
+        "Filter misses that turned out to be live links"
+    ).unwrap();
+
+    pub static ref NEGATIVE_CACHE_HITS: Counter = register_counter!(
+        "negative_cache_hits_total",
+        "Lookups answered from the negative cache"
+    ).unwrap();
+
+    pub static ref REPLICA_AVAILABLE: Gauge = register_gauge!(
+        "replica_available",
+        "1 when lookups are served by the read replica, 0 when falling back to the writer"
+    ).unwrap();
+
+    // Rate limit checks decided locally because Redis could not be reached
+    pub static ref RATE_LIMIT_FALLBACKS: Counter = register_counter!(
+        "rate_limit_fallbacks_total",
+        "Distributed rate limit checks served by the local limiter"
+    ).unwrap();
+
+    pub static ref CLICKS_WRITTEN: Counter = register_counter!(
+        "clicks_written_total",
+        "Click events stored in Postgres"
+    ).unwrap();
+
+    // Clicks lost to a full queue or a failed batch write
+    pub static ref CLICKS_DROPPED: Counter = register_counter!(
+        "clicks_dropped_total",
+        "Click events dropped before reaching Postgres"
+    ).unwrap();
+
+    // State of the rolling partition window, updated by the maintenance task
+    pub static ref FILTER_WINDOW_PARTITIONS: Gauge = register_gauge!(
+        "filter_window_partitions",
+        "Monthly partition filters currently held"
+    ).unwrap();
+
+    pub static ref FILTER_FUTURE_START: Gauge = register_gauge!(
+        "filter_future_partition_start_seconds",
+        "Unix time of the first day covered by the future partition"
+    ).unwrap();
+
+    pub static ref FILTER_FUTURE_ITEMS: Gauge = register_gauge!(
+        "filter_future_partition_items",
+        "Short codes held by the future partition filter"
+    ).unwrap();
+
+    pub static ref PARTITION_MAINTENANCE_LAST_SUCCESS: Gauge = register_gauge!(
+        "partition_maintenance_last_success_seconds",
+        "Unix time of the last successful partition window maintenance"
+    ).unwrap();
+
+    // Changes received from other instances through the filter change feed
+    pub static ref FILTER_SYNC_EVENTS_APPLIED: Counter = register_counter!(
+        "filter_sync_events_applied_total",
+        "Filter changes applied from other instances"
+    ).unwrap();
+
+    pub static ref FILTER_SYNC_PUBLISH_FAILURES: Counter = register_counter!(
+        "filter_sync_publish_failures_total",
+        "Filter changes that could not be published to the other instances"
+    ).unwrap();
+
+    // Changes to `urls` made outside CargoCut, received through LISTEN/NOTIFY
+    pub static ref URL_CHANGES_APPLIED: Counter = register_counter!(
+        "url_changes_applied_total",
+        "External changes to urls applied to the cache and filter"
+    ).unwrap();
+
+    // Each reconnect may have missed notifications
+    pub static ref URL_CHANGE_LISTENER_RECONNECTS: Counter = register_counter!(
+        "url_change_listener_reconnects_total",
+        "Times the urls change listener lost its connection"
+    ).unwrap();
+
+    // Results of the last filter audit, per partition where it applies
+    pub static ref FILTER_AUDIT_MISSING: GaugeVec = register_gauge_vec!(
+        "filter_audit_missing",
+        "Sampled live codes the filter did not contain",
+        &["partition"]
+    ).unwrap();
+
+    pub static ref FILTER_FILL_RATIO: GaugeVec = register_gauge_vec!(
+        "filter_fill_ratio",
+        "Items held by a partition filter over its capacity",
+        &["partition"]
+    ).unwrap();
+
+    pub static ref FILTER_OBSERVED_FALSE_POSITIVE_RATE: GaugeVec = register_gauge_vec!(
+        "filter_observed_false_positive_rate",
+        "Share of random unused codes a partition filter accepted in the last audit",
+        &["partition"]
+    ).unwrap();
+
+    pub static ref FILTER_AUDIT_REPAIRED: Counter = register_counter!(
+        "filter_audit_repaired_total",
+        "Missing codes the audit put back in the filter"
+    ).unwrap();
+
+    pub static ref FILTER_AUDIT_LAST_SUCCESS: Gauge = register_gauge!(
+        "filter_audit_last_success_seconds",
+        "Unix time of the last completed filter audit"
+    ).unwrap();
+
     pub static ref MEMORY_USAGE: Gauge = register_gauge!(
         "memory_usage_bytes",
         "Memory usage in bytes"
     ).unwrap();
 }
 // Helper function to get endpoint name from path
+#[allow(dead_code)]
 pub fn get_endpoint_name(path: &str) -> &str {
     if path.starts_with("/api/urls") {
         "create_url"
@@ -1,16 +1,231 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
 use serde::Deserialize;
 use serde::Serialize;
+use std::str::FromStr;
 
+// At most one of months_valid, expires_at and expires_in may be given;
+// without any the link lives for one month
 #[derive(Deserialize)]
 pub struct CreateUrl {
     pub long_url: String,
-    pub months_valid: Option<u32>, // Optional expiry days
+    pub months_valid: Option<u32>,
+    pub expires_at: Option<ExpiresAt>,
+    pub expires_in: Option<ExpiresIn>,
     pub custom_short_code: Option<String>,
+    pub redirect_type: Option<u16>, // 301, 302, 307 or 308
+}
+
+// An RFC 3339 timestamp, or "never"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiresAt {
+    Never,
+    At(chrono::DateTime<chrono::Utc>),
+}
+
+impl FromStr for ExpiresAt {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "never" {
+            return Ok(ExpiresAt::Never);
+        }
+        chrono::DateTime::parse_from_rfc3339(value)
+            .map(|at| ExpiresAt::At(at.with_timezone(&chrono::Utc)))
+            .map_err(|e| format!("expected an RFC 3339 timestamp or \"never\": {}", e))
+    }
+}
+
+impl<'de> Deserialize<'de> for ExpiresAt {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct ExpiresIn {
+    #[serde(default)]
+    pub days: u32,
+    #[serde(default)]
+    pub hours: u32,
 }
 
 #[derive(Serialize)]
 pub struct UrlResponse {
     pub short_code: String,
     pub long_url: String,
-    pub expiry_date: String,
+    pub expiry_date: String, // "never" for links that don't expire
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub redirect_type: u16,
+}
+
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub status: &'static str, // "created" or "error"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<UrlResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchCreateResponse {
+    pub created: usize,
+    pub failed: usize,
+    pub results: Vec<BatchItemResult>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUrl {
+    pub long_url: Option<String>,
+    pub redirect_type: Option<u16>,
+    pub expiry_date: Option<chrono::NaiveDate>,
+}
+
+#[derive(Deserialize)]
+pub struct ExtendUrl {
+    pub months: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    pub granularity: Granularity,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct StatsBucket {
+    pub bucket: chrono::DateTime<chrono::Utc>,
+    pub clicks: i64,
+    pub unique_visitors: u64,
+}
+
+#[derive(Serialize)]
+pub struct TopValue {
+    pub value: String,
+    pub clicks: i64,
+}
+
+#[derive(Serialize)]
+pub struct DayCount {
+    pub day: chrono::NaiveDate,
+    pub clicks: i64,
+}
+
+// Real-time counts from the Redis counters, including clicks not yet rolled up
+#[derive(Serialize)]
+pub struct LiveClicks {
+    pub total_clicks: i64,
+    pub today_clicks: i64,
+    pub daily: Vec<DayCount>, // days in the requested range with clicks
+}
+
+#[derive(Serialize)]
+pub struct LinkStats {
+    pub short_code: String,
+    pub total_clicks: i64,
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    pub clicks: i64,
+    pub unique_visitors: u64,     // HyperLogLog estimate
+    pub series: Vec<StatsBucket>, // buckets without clicks are omitted
+    pub top_referrers: Vec<TopValue>,
+    pub top_user_agents: Vec<TopValue>,
+    // Clicks after this instant are not in the rollups yet
+    pub rolled_up_to: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live: Option<LiveClicks>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    // Defaults to filter.audit_repair
+    pub repair: Option<bool>,
+}
+
+// One `urls` partition, or a filter with no table yet
+#[derive(Serialize)]
+pub struct PartitionAudit {
+    pub partition: String,
+    pub sampled: u64,
+    pub missing: u64,
+    pub repaired: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter_items: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill_ratio: Option<f64>, // filter items over capacity
+    // Probes this partition's filter accepted; only for filters a lookup checks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub false_positives: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_false_positive_rate: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditReport {
+    pub audited_at: chrono::DateTime<chrono::Utc>,
+    pub partitions: Vec<PartitionAudit>,
+    pub sampled: u64,
+    pub missing: u64,
+    pub repaired: u64,
+    pub probes: u64,
+    pub false_positives: u64,
+    pub observed_false_positive_rate: f64,
+    // Per filter; a lookup checks every filter in the window
+    pub configured_false_positive_rate: f64,
+    // What `observed_false_positive_rate` should be given how many filters a lookup checks
+    pub expected_false_positive_rate: f64,
+}
+
+// HTTP status used when redirecting a short code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectType {
+    MovedPermanently,
+    Found,
+    Temporary,
+    Permanent,
+}
+
+impl RedirectType {
+    pub fn from_status(status: u16) -> Option<Self> {
+        match status {
+            301 => Some(RedirectType::MovedPermanently),
+            302 => Some(RedirectType::Found),
+            307 => Some(RedirectType::Temporary),
+            308 => Some(RedirectType::Permanent),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RedirectType::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectType::Found => StatusCode::FOUND,
+            RedirectType::Temporary => StatusCode::TEMPORARY_REDIRECT,
+            RedirectType::Permanent => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.status().as_u16()
+    }
+
+    pub fn redirect(&self, long_url: &str) -> Response {
+        (self.status(), [(header::LOCATION, long_url)]).into_response()
+    }
 }
@@ -0,0 +1,546 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::{
+    clock::Clock, middleware::StateInformationMiddleware, DefaultKeyedRateLimiter, Quota,
+    RateLimiter,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+use tower_governor::{key_extractor::KeyExtractor, GovernorError};
+
+use crate::auth::ApiKey;
+use crate::config::TierConfig;
+use crate::errors::AppError;
+use crate::metrics::RATE_LIMIT_FALLBACKS;
+use crate::redis::{RateLimitDecision, RedisManager};
+use crate::AppState;
+
+// IPs and CIDR ranges of proxies allowed to set X-Forwarded-For
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        let mut networks = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (entry.as_str(), None),
+            };
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| format!("invalid proxy address {:?}", entry))?;
+            let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|p| *p <= max_prefix)
+                    .ok_or_else(|| format!("invalid prefix length in {:?}", entry))?,
+                None => max_prefix,
+            };
+            networks.push((addr, prefix));
+        }
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            })
+    }
+
+    // Walk X-Forwarded-For right to left past trusted hops; the first untrusted
+    // address is the client. Headers from untrusted peers are ignored entirely.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        let hops = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        for hop in hops.iter().rev() {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+// Rate-limit key for the redirect routes: the client IP as seen through trusted proxies
+#[derive(Debug, Clone)]
+pub struct ClientIpKeyExtractor {
+    pub trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, req: &axum::http::Request<T>) -> Result<Self::Key, GovernorError> {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .ok_or(GovernorError::UnableToExtractKey)?;
+        Ok(self.trusted_proxies.client_ip(peer, req.headers()))
+    }
+}
+
+// Render tower_governor rejections in the same JSON shape as every other error
+pub fn governor_error_response(error: GovernorError) -> Response {
+    match error {
+        GovernorError::TooManyRequests { wait_time, headers } => {
+            let mut response = AppError::RateLimited {
+                retry_after_secs: wait_time.max(1),
+            }
+            .into_response();
+            if let Some(headers) = headers {
+                response.headers_mut().extend(headers);
+            }
+            response
+        }
+        GovernorError::UnableToExtractKey => {
+            AppError::Internal("unable to extract rate limit key".to_string()).into_response()
+        }
+        GovernorError::Other { msg, .. } => {
+            AppError::Internal(msg.unwrap_or_else(|| "rate limiter error".to_string()))
+                .into_response()
+        }
+    }
+}
+
+type TierLimiter = DefaultKeyedRateLimiter<i64, StateInformationMiddleware>;
+
+// Per-key request limits for `/api/*`, one keyed limiter per tier. With Redis the
+// limits are shared by every instance and the local limiters are only a fallback.
+pub struct ApiRateLimiter {
+    tiers: HashMap<String, (TierConfig, TierLimiter)>,
+    redis: Option<RedisManager>,
+}
+
+impl ApiRateLimiter {
+    pub fn new(tiers: &BTreeMap<String, TierConfig>, redis: Option<RedisManager>) -> Self {
+        let tiers = tiers
+            .iter()
+            .map(|(name, tier)| {
+                // Checked to be non-zero when the config is validated
+                let rate = NonZeroU32::new(tier.requests_per_second).unwrap_or(NonZeroU32::MIN);
+                let burst = NonZeroU32::new(tier.burst).unwrap_or(NonZeroU32::MIN);
+                let limiter = RateLimiter::keyed(Quota::per_second(rate).allow_burst(burst))
+                    .with_middleware::<StateInformationMiddleware>();
+                (name.clone(), (tier.clone(), limiter))
+            })
+            .collect();
+        ApiRateLimiter { tiers, redis }
+    }
+
+    fn tier(&self, name: &str) -> (&str, &TierConfig, &TierLimiter) {
+        let (name, (tier, limiter)) = self
+            .tiers
+            .get_key_value(name)
+            .or_else(|| self.tiers.get_key_value("default"))
+            .expect("rate_limit.tiers must contain a default tier");
+        (name, tier, limiter)
+    }
+
+    pub async fn check(&self, key: &ApiKey) -> Result<HeaderMap, AppError> {
+        let (name, tier, limiter) = self.tier(&key.tier);
+
+        if let Some(redis) = &self.redis {
+            let period = Duration::from_secs(1) / tier.requests_per_second.max(1);
+            let bucket = format!("api:{}:{}", name, key.id);
+            match redis.rate_limit(&bucket, period, tier.burst).await {
+                Ok(decision) => return decision_headers(decision, tier.burst),
+                Err(e) => {
+                    RATE_LIMIT_FALLBACKS.inc();
+                    tracing::warn!("Distributed rate limit unavailable, using local: {}", e);
+                }
+            }
+        }
+
+        match limiter.check_key(&key.id) {
+            Ok(snapshot) => Ok(headers([
+                ("x-ratelimit-limit", tier.burst as u64),
+                (
+                    "x-ratelimit-remaining",
+                    snapshot.remaining_burst_capacity() as u64,
+                ),
+            ])),
+            Err(not_until) => {
+                let wait = not_until.wait_time_from(limiter.clock().now());
+                Err(AppError::RateLimited {
+                    retry_after_secs: wait.as_secs().max(1),
+                })
+            }
+        }
+    }
+
+    // Links per day for this key: its own override, else its tier's quota
+    pub fn daily_quota(&self, key: &ApiKey) -> Option<u64> {
+        key.daily_quota
+            .map(|quota| quota.max(0) as u64)
+            .or(self.tier(&key.tier).1.daily_quota)
+    }
+
+    pub fn retain_recent(&self) {
+        for (_, limiter) in self.tiers.values() {
+            limiter.retain_recent();
+        }
+    }
+}
+
+// Middleware for `/api/*`: authenticate, apply the key's tier limit and hand the
+// key on to the handler through the request extensions
+pub async fn api_rate_limit(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    match state.api_limiter.check(&api_key).await {
+        Ok(limit_headers) => {
+            req.extensions_mut().insert(api_key);
+            let mut response = next.run(req).await;
+            response.headers_mut().extend(limit_headers);
+            response
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Count `count` new links against the key's daily quota, returning quota headers
+pub async fn consume_quota(
+    state: &AppState,
+    key: &ApiKey,
+    count: u64,
+) -> Result<HeaderMap, AppError> {
+    let Some(limit) = state.api_limiter.daily_quota(key) else {
+        return Ok(HeaderMap::new());
+    };
+
+    let now = chrono::Utc::now();
+    let reset_secs = now
+        .date_naive()
+        .succ_opt()
+        .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+        .map(|midnight| (midnight - now.naive_utc()).num_seconds().max(0) as u64)
+        .unwrap_or(0);
+
+    let used = match state
+        .redis
+        .incr_quota(key.id, now.date_naive(), count as i64)
+        .await
+    {
+        Ok(used) => used.max(0) as u64,
+        Err(e) => {
+            // Quotas are best effort; an unavailable Redis must not block link creation
+            tracing::warn!("Quota check skipped, Redis unavailable: {}", e);
+            return Ok(HeaderMap::new());
+        }
+    };
+
+    if used > limit {
+        tracing::info!("API key {} ({}) is over its daily quota", key.id, key.name);
+        if let Err(e) = state
+            .redis
+            .incr_quota(key.id, now.date_naive(), -(count as i64))
+            .await
+        {
+            tracing::warn!("Failed to roll back quota usage: {}", e);
+        }
+        return Err(AppError::QuotaExceeded { limit, reset_secs });
+    }
+
+    Ok(headers([
+        ("x-quota-limit", limit),
+        ("x-quota-remaining", limit - used),
+        ("x-quota-reset", reset_secs),
+    ]))
+}
+
+// Tower layer limiting requests per key through Redis so the limit holds across
+// replicas. Used in place of `GovernorLayer`; while Redis is down each instance
+// falls back to its own governor limiter.
+#[derive(Clone)]
+pub struct DistributedRateLimitLayer<K: KeyExtractor> {
+    pub redis: RedisManager,
+    pub key_extractor: K,
+    // Bucket namespace in Redis, e.g. "redirect"
+    pub scope: &'static str,
+    pub period: Duration,
+    pub burst: u32,
+    pub fallback: Arc<DefaultKeyedRateLimiter<K::Key, StateInformationMiddleware>>,
+}
+
+impl<K: KeyExtractor, S> Layer<S> for DistributedRateLimitLayer<K> {
+    type Service = DistributedRateLimit<K, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DistributedRateLimit {
+            limit: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DistributedRateLimit<K: KeyExtractor, S> {
+    limit: DistributedRateLimitLayer<K>,
+    inner: S,
+}
+
+impl<K> DistributedRateLimitLayer<K>
+where
+    K: KeyExtractor,
+    K::Key: Display,
+{
+    async fn check(&self, key: K::Key) -> Result<HeaderMap, AppError> {
+        let bucket = format!("{}:{}", self.scope, key);
+        match self
+            .redis
+            .rate_limit(&bucket, self.period, self.burst)
+            .await
+        {
+            Ok(decision) => return decision_headers(decision, self.burst),
+            Err(e) => {
+                RATE_LIMIT_FALLBACKS.inc();
+                tracing::warn!("Distributed rate limit unavailable, using local: {}", e);
+            }
+        }
+
+        match self.fallback.check_key(&key) {
+            Ok(snapshot) => Ok(headers([
+                ("x-ratelimit-limit", self.burst as u64),
+                (
+                    "x-ratelimit-remaining",
+                    snapshot.remaining_burst_capacity() as u64,
+                ),
+            ])),
+            Err(not_until) => {
+                let wait = not_until.wait_time_from(self.fallback.clock().now());
+                Err(AppError::RateLimited {
+                    retry_after_secs: wait.as_secs().max(1),
+                })
+            }
+        }
+    }
+}
+
+impl<K, S> Service<Request> for DistributedRateLimit<K, S>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    K::Key: Display + Send + Sync + 'static,
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let limit = self.limit.clone();
+        // Take the service that was driven to readiness, leave a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let key = match limit.key_extractor.extract(&req) {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::warn!("Unable to extract rate limit key: {}", e);
+                    return Ok(AppError::Internal(e.to_string()).into_response());
+                }
+            };
+
+            match limit.check(key).await {
+                Ok(limit_headers) => {
+                    let mut response = inner.call(req).await?;
+                    response.headers_mut().extend(limit_headers);
+                    Ok(response)
+                }
+                Err(e) => Ok(e.into_response()),
+            }
+        })
+    }
+}
+
+fn decision_headers(decision: RateLimitDecision, burst: u32) -> Result<HeaderMap, AppError> {
+    if !decision.allowed {
+        return Err(AppError::RateLimited {
+            retry_after_secs: decision.retry_after.as_secs().max(1),
+        });
+    }
+    Ok(headers([
+        ("x-ratelimit-limit", burst as u64),
+        ("x-ratelimit-remaining", decision.remaining),
+    ]))
+}
+
+fn headers<const N: usize>(values: [(&'static str, u64); N]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in values {
+        map.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies(entries: &[&str]) -> TrustedProxies {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        TrustedProxies::parse(&entries).unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rejects_bad_entries() {
+        for entry in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "proxy.local",
+        ] {
+            assert!(
+                TrustedProxies::parse(&[entry.to_string()]).is_err(),
+                "{} should be rejected",
+                entry
+            );
+        }
+    }
+
+    #[test]
+    fn cidr_masks() {
+        let private = proxies(&["10.1.0.0/16", "192.168.1.7", "172.16.0.0/12"]);
+        assert!(private.contains(ip("10.1.255.255")));
+        assert!(private.contains(ip("192.168.1.7")));
+        assert!(private.contains(ip("172.31.0.1")));
+        assert!(!private.contains(ip("10.2.0.1")));
+        assert!(!private.contains(ip("192.168.1.8")));
+        assert!(!private.contains(ip("172.32.0.1")));
+
+        let everything = proxies(&["0.0.0.0/0"]);
+        assert!(everything.contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn v4_and_v6_networks_only_match_their_own_family() {
+        let mixed = proxies(&["10.0.0.0/8", "fd00::/8"]);
+        assert!(mixed.contains(ip("10.0.0.1")));
+        assert!(mixed.contains(ip("fd12:3456::1")));
+        assert!(!mixed.contains(ip("fe80::1")));
+        // An IPv4-mapped IPv6 address is not the IPv4 address
+        assert!(!mixed.contains(ip("::ffff:10.0.0.1")));
+
+        let all_v6 = proxies(&["::/0"]);
+        assert!(all_v6.contains(ip("2001:db8::1")));
+        assert!(!all_v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn untrusted_peer_cannot_spoof_forwarded_for() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["1.2.3.4"]);
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.9"), &headers),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped_right_to_left() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        // The client put 1.2.3.4 in the header itself; the first untrusted hop wins
+        let headers = forwarded_for(&["1.2.3.4, 198.51.100.7, 10.0.0.5"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+
+        // Repeated headers are read as one list
+        let headers = forwarded_for(&["198.51.100.7", "10.0.0.5"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+
+        let headers = forwarded_for(&["2001:db8::7, 10.0.0.5"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8::7")
+        );
+    }
+
+    #[test]
+    fn trusted_peer_without_a_client_hop() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+
+        // Every hop trusted: the leftmost is as far back as we can see
+        let headers = forwarded_for(&["10.0.0.9, 10.0.0.5"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.9"));
+
+        // Garbage stops the walk at the last address that parsed
+        let headers = forwarded_for(&["unknown, 10.0.0.5"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.5"));
+    }
+}
@@ -1,33 +1,381 @@
-use redis::RedisResult;
-use redis::{Client, Commands, Connection};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use chrono::{DateTime, NaiveDate, Utc};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::streams::{StreamMaxlen, StreamRangeReply};
+use redis::{AsyncCommands, Client, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::config::RedisConfig;
+use crate::expiry::Expiry;
+use crate::filter_sync::FilterEvent;
+
+// Bump whenever the layout of `CachedUrl` changes. The version is part of the
+// key, so old and new encodings never overwrite each other during a rollout.
+pub const CACHE_SCHEMA_VERSION: u32 = 2;
+
+/// Value stored in Redis for a short code
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedUrl {
+    pub long_url: String,
+    pub expiry_date: NaiveDate,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    pub redirect_type: Option<u16>,
+    #[serde(default)]
+    pub flags: u32,
+}
+
+impl CachedUrl {
+    pub fn expiry(&self) -> Expiry {
+        Expiry {
+            expiry_date: self.expiry_date,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // GCRA over a single key holding the theoretical arrival time in milliseconds.
+    // Uses the Redis clock so every instance agrees on "now".
+    // Returns {allowed, remaining, retry_after_ms}.
+    static ref GCRA_SCRIPT: redis::Script = redis::Script::new(
+        r#"
+        local emission = tonumber(ARGV[1])
+        local burst = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+        local tat = tonumber(redis.call('GET', KEYS[1])) or now
+        if tat < now then
+            tat = now
+        end
+        local new_tat = tat + emission
+        local allow_at = new_tat - emission * burst
+        if allow_at > now then
+            return {0, 0, allow_at - now}
+        end
+
+        redis.call('SET', KEYS[1], new_tat, 'PX', new_tat - now)
+        return {1, math.floor((now - allow_at) / emission), 0}
+        "#,
+    );
+}
+
+/// Outcome of a shared rate limit check
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub remaining: u64,
+    pub retry_after: Duration,
+}
+
 #[derive(Clone)]
 pub struct RedisManager {
-    pub conn: Arc<Mutex<Connection>>,
+    // Kept for dedicated connections, e.g. blocking stream reads
+    client: Client,
+    // Multiplexed and cheap to clone; reconnects on its own after a dropped
+    // connection, and fails calls that outlive the configured timeout
+    conn: ConnectionManager,
+    pub key_prefix: String,
+    pub negative_ttl_secs: u64,
 }
 
 impl RedisManager {
-    /// Initialize a Redis Cluster connection
-    pub async fn new(redis_urls: &str) -> RedisResult<Self> {
-        let client = Client::open(redis_urls)?;
-        let conn = client.get_connection()?;
+    /// Initialize a Redis connection
+    pub async fn new(config: &RedisConfig) -> RedisResult<Self> {
+        let client = Client::open(config.url.as_str())?;
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let conn = client
+            .get_connection_manager_with_config(
+                ConnectionManagerConfig::new()
+                    .set_connection_timeout(timeout)
+                    .set_response_timeout(timeout),
+            )
+            .await?;
         Ok(Self {
-            conn: Arc::new(Mutex::new(conn)),
+            client,
+            conn,
+            key_prefix: config.key_prefix.clone(),
+            negative_ttl_secs: config.negative_ttl_secs,
         })
     }
 
-    /// Store short URL → long URL mapping
-    pub async fn set_short_url(&self, short_code: &str, long_url: &str) -> RedisResult<()> {
-        let mut conn = self.conn.lock().await;
-        conn.set::<&str, &str, ()>(short_code, long_url)?;
+    /// Check the connection is alive
+    pub async fn ping(&self) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("PING").query_async::<String>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Build the namespaced key for a short code: `<key_prefix>:v<CACHE_SCHEMA_VERSION>:url:<short_code>`
+    pub fn url_key(&self, short_code: &str) -> String {
+        format!(
+            "{}:v{}:url:{}",
+            self.key_prefix, CACHE_SCHEMA_VERSION, short_code
+        )
+    }
+
+    /// Build the key marking a short code as confirmed missing
+    pub fn miss_key(&self, short_code: &str) -> String {
+        format!(
+            "{}:v{}:miss:{}",
+            self.key_prefix, CACHE_SCHEMA_VERSION, short_code
+        )
+    }
+
+    /// Store short URL → cached link mapping
+    pub async fn set_short_url(&self, short_code: &str, cached: &CachedUrl) -> RedisResult<()> {
+        let value = serde_json::to_string(cached).map_err(|e| {
+            redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "failed to encode cached url",
+                e.to_string(),
+            ))
+        })?;
+        let mut conn = self.conn.clone();
+        conn.set::<String, String, ()>(self.url_key(short_code), value)
+            .await?;
+        // A freshly created code must not keep answering 404 from an old miss
+        conn.del::<String, ()>(self.miss_key(short_code)).await?;
+        Ok(())
+    }
+
+    /// Store many mappings in one pipelined round trip
+    pub async fn set_short_urls(&self, entries: &[(String, CachedUrl)]) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for (short_code, cached) in entries {
+            let value = serde_json::to_string(cached).map_err(|e| {
+                redis::RedisError::from((
+                    redis::ErrorKind::TypeError,
+                    "failed to encode cached url",
+                    e.to_string(),
+                ))
+            })?;
+            pipe.set(self.url_key(short_code), value)
+                .ignore()
+                .del(self.miss_key(short_code))
+                .ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Retrieve cached link from short code
+    pub async fn get_short_url(&self, short_code: &str) -> RedisResult<Option<CachedUrl>> {
+        let mut conn = self.conn.clone();
+        let result: Option<String> = conn.get(self.url_key(short_code)).await?;
+
+        // An undecodable value is treated as a miss so the caller falls back to
+        // the database instead of failing the redirect.
+        Ok(result.and_then(|value| serde_json::from_str(&value).ok()))
+    }
+
+    /// Drop the cached link for a short code, and any cached miss
+    pub async fn delete_short_url(&self, short_code: &str) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(&[self.url_key(short_code), self.miss_key(short_code)])
+            .await?;
         Ok(())
     }
 
-    /// Retrieve long URL from short code
-    pub async fn get_long_url(&self, short_code: &str) -> RedisResult<Option<String>> {
-        let mut conn = self.conn.lock().await;
-        let result = conn.get(short_code)?;
-        Ok(result)
+    /// Remember that a short code does not exist for `negative_ttl_secs`
+    pub async fn set_negative(&self, short_code: &str) -> RedisResult<()> {
+        if self.negative_ttl_secs == 0 {
+            return Ok(());
+        }
+        let mut conn = self.conn.clone();
+        conn.set_ex::<String, u8, ()>(self.miss_key(short_code), 1, self.negative_ttl_secs)
+            .await?;
+        Ok(())
+    }
+
+    /// Check whether a short code was recently confirmed missing
+    pub async fn is_negative(&self, short_code: &str) -> RedisResult<bool> {
+        if self.negative_ttl_secs == 0 {
+            return Ok(false);
+        }
+        let mut conn = self.conn.clone();
+        conn.exists(self.miss_key(short_code)).await
+    }
+
+    /// Add `by` to a key's link count for `day`, returning the new total
+    pub async fn incr_quota(&self, key_id: i64, day: NaiveDate, by: i64) -> RedisResult<i64> {
+        let key = format!(
+            "{}:quota:{}:{}",
+            self.key_prefix,
+            key_id,
+            day.format("%Y%m%d")
+        );
+        let mut conn = self.conn.clone();
+        // Keep the counter a little past the end of its day, then let Redis drop it
+        let (used,): (i64,) = redis::pipe()
+            .atomic()
+            .incr(&key, by)
+            .expire(&key, 2 * 24 * 60 * 60)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(used)
+    }
+
+    /// Take one request from the bucket shared by all instances. A token is
+    /// replenished every `period`, up to `burst` stored.
+    pub async fn rate_limit(
+        &self,
+        bucket: &str,
+        period: Duration,
+        burst: u32,
+    ) -> RedisResult<RateLimitDecision> {
+        let key = format!("{}:ratelimit:{}", self.key_prefix, bucket);
+        let emission_ms = period.as_millis().max(1) as u64;
+        let mut conn = self.conn.clone();
+        let (allowed, remaining, retry_after_ms): (i64, i64, i64) = GCRA_SCRIPT
+            .key(key)
+            .arg(emission_ms)
+            .arg(burst)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(RateLimitDecision {
+            allowed: allowed == 1,
+            remaining: remaining.max(0) as u64,
+            retry_after: Duration::from_millis(retry_after_ms.max(0) as u64),
+        })
+    }
+
+    fn click_counter_key(&self, short_code: &str, day: NaiveDate) -> String {
+        format!(
+            "{}:clicks:{}:{}",
+            self.key_prefix,
+            day.format("%Y%m%d"),
+            short_code
+        )
+    }
+
+    fn dirty_counters_key(&self) -> String {
+        format!("{}:clicks:dirty", self.key_prefix)
+    }
+
+    /// Add counts to the per-day counters, e.g. a batch of clicks or a failed flush put back
+    pub async fn add_click_counts(&self, counts: &[(String, NaiveDate, i64)]) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for (short_code, day, count) in counts {
+            let key = self.click_counter_key(short_code, *day);
+            // Counters are flushed within seconds; the TTL only bounds what a
+            // long database outage can pile up
+            pipe.incr(&key, *count)
+                .ignore()
+                .expire(&key, 7 * 24 * 60 * 60)
+                .ignore()
+                .sadd(
+                    self.dirty_counters_key(),
+                    format!("{}:{}", day.format("%Y%m%d"), short_code),
+                )
+                .ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Take up to `max` pending counters, resetting them in Redis
+    pub async fn take_click_counts(
+        &self,
+        max: usize,
+    ) -> RedisResult<Vec<(String, NaiveDate, i64)>> {
+        let mut conn = self.conn.clone();
+        let members: Vec<String> = redis::cmd("SPOP")
+            .arg(self.dirty_counters_key())
+            .arg(max)
+            .query_async(&mut conn)
+            .await?;
+
+        let entries: Vec<(String, NaiveDate)> = members
+            .iter()
+            .filter_map(|member| {
+                let (day, short_code) = member.split_once(':')?;
+                let day = NaiveDate::parse_from_str(day, "%Y%m%d").ok()?;
+                Some((short_code.to_string(), day))
+            })
+            .collect();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for (short_code, day) in &entries {
+            pipe.get_del(self.click_counter_key(short_code, *day));
+        }
+        let counts: Vec<Option<i64>> = pipe.query_async(&mut conn).await?;
+
+        // A counter already taken by another instance reads as missing
+        Ok(entries
+            .into_iter()
+            .zip(counts)
+            .filter_map(|((short_code, day), count)| Some((short_code, day, count?)))
+            .collect())
+    }
+
+    /// Clicks counted for `short_code` on each of `days` but not flushed yet
+    pub async fn pending_clicks(
+        &self,
+        short_code: &str,
+        days: &[NaiveDate],
+    ) -> RedisResult<Vec<i64>> {
+        if days.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = days
+            .iter()
+            .map(|day| self.click_counter_key(short_code, *day))
+            .collect();
+        let mut conn = self.conn.clone();
+        let counts: Vec<Option<i64>> = redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+        Ok(counts.into_iter().map(|count| count.unwrap_or(0)).collect())
+    }
+
+    /// Stream of filter changes shared by every instance
+    pub fn filter_events_key(&self) -> String {
+        format!("{}:filter:events", self.key_prefix)
+    }
+
+    /// Append filter changes to the shared stream, trimmed to about `max_len` entries
+    pub async fn publish_filter_events(
+        &self,
+        origin: &str,
+        events: &[FilterEvent],
+        max_len: usize,
+    ) -> RedisResult<()> {
+        let key = self.filter_events_key();
+        let mut pipe = redis::pipe();
+        for event in events {
+            pipe.xadd_maxlen(
+                &key,
+                StreamMaxlen::Approx(max_len),
+                "*",
+                &event.fields(origin),
+            )
+            .ignore();
+        }
+        let mut conn = self.conn.clone();
+        pipe.query_async::<()>(&mut conn).await
+    }
+
+    /// Id of the newest (`newest = true`) or oldest entry still in the filter stream
+    pub async fn filter_event_bound(&self, newest: bool) -> RedisResult<Option<String>> {
+        let key = self.filter_events_key();
+        let mut conn = self.conn.clone();
+        let reply: StreamRangeReply = if newest {
+            conn.xrevrange_count(key, "+", "-", 1).await?
+        } else {
+            conn.xrange_count(key, "-", "+", 1).await?
+        };
+        Ok(reply.ids.into_iter().next().map(|entry| entry.id))
+    }
+
+    /// Separate connection for blocking reads, so they don't hold up `conn`
+    pub async fn dedicated_connection(&self) -> RedisResult<redis::aio::MultiplexedConnection> {
+        self.client.get_multiplexed_async_connection().await
     }
 }
@@ -0,0 +1,207 @@
+use axum::extract::State;
+use chrono::{DateTime, Duration, DurationRound, NaiveDate, Utc};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::auth::ApiKey;
+use crate::errors::AppError;
+use crate::extract::{Json, Path, Query};
+use crate::hll::HyperLogLog;
+use crate::links::fetch_link;
+use crate::models::{
+    DayCount, Granularity, LinkStats, LiveClicks, StatsBucket, StatsQuery, TopValue,
+};
+use crate::AppState;
+
+// Longest range a single request may cover, per granularity
+const MAX_HOURLY_RANGE_DAYS: i64 = 31;
+const MAX_DAILY_RANGE_DAYS: i64 = 366;
+const TOP_LIMIT: i64 = 10;
+
+// Stats come from the click rollups, so they trail real time by up to the rollup
+// interval plus delay; `rolled_up_to` in the response says how far they reach.
+// `live` adds the real-time Redis counters when they are enabled.
+pub async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    api_key: ApiKey,
+    Path(short_code): Path<String>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<LinkStats>, AppError> {
+    fetch_link(&state, &short_code, &api_key).await?;
+
+    let (default_range, max_range, step) = match query.granularity {
+        Granularity::Hour => (
+            Duration::hours(48),
+            Duration::days(MAX_HOURLY_RANGE_DAYS),
+            Duration::hours(1),
+        ),
+        Granularity::Day => (
+            Duration::days(30),
+            Duration::days(MAX_DAILY_RANGE_DAYS),
+            Duration::days(1),
+        ),
+    };
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - default_range);
+    if from >= to {
+        return Err(AppError::Validation {
+            field: "from",
+            message: "must be before to".to_string(),
+        });
+    }
+    if to - from > max_range {
+        return Err(AppError::Validation {
+            field: "from",
+            message: format!(
+                "range is limited to {} days at this granularity",
+                max_range.num_days()
+            ),
+        });
+    }
+
+    let pool = state.db.reader();
+
+    let total_clicks: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(sum(clicks), 0)::bigint FROM click_rollups_hourly WHERE short_code = $1",
+    )
+    .bind(&short_code)
+    .fetch_one(pool)
+    .await?;
+
+    let rolled_up_to: DateTime<Utc> =
+        sqlx::query_scalar("SELECT rolled_up_to FROM click_rollup_state")
+            .fetch_one(pool)
+            .await?;
+
+    let hours: Vec<(DateTime<Utc>, i64, Vec<u8>)> = sqlx::query_as(
+        "SELECT bucket, clicks, visitors FROM click_rollups_hourly
+         WHERE short_code = $1 AND bucket >= $2 AND bucket < $3
+         ORDER BY bucket",
+    )
+    .bind(&short_code)
+    .bind(from.duration_trunc(Duration::hours(1)).unwrap_or(from))
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let mut overall = HyperLogLog::new();
+    let mut buckets: BTreeMap<DateTime<Utc>, (i64, HyperLogLog)> = BTreeMap::new();
+    for (hour, clicks, visitors) in hours {
+        let sketch = HyperLogLog::from_bytes(&visitors);
+        overall.merge(&sketch);
+
+        let bucket = hour.duration_trunc(step).unwrap_or(hour);
+        let entry = buckets.entry(bucket).or_default();
+        entry.0 += clicks;
+        entry.1.merge(&sketch);
+    }
+
+    let series: Vec<StatsBucket> = buckets
+        .into_iter()
+        .map(|(bucket, (clicks, sketch))| StatsBucket {
+            bucket,
+            clicks,
+            unique_visitors: sketch.estimate(),
+        })
+        .collect();
+
+    let live = if state.clicks.counts_live() {
+        Some(live_clicks(&state, &short_code, from, to).await?)
+    } else {
+        None
+    };
+
+    Ok(Json(LinkStats {
+        clicks: series.iter().map(|bucket| bucket.clicks).sum(),
+        unique_visitors: overall.estimate(),
+        top_referrers: top_values(pool, &short_code, "referrer", from, to).await?,
+        top_user_agents: top_values(pool, &short_code, "user_agent", from, to).await?,
+        short_code,
+        total_clicks,
+        from,
+        to,
+        series,
+        rolled_up_to,
+        live,
+    }))
+}
+
+// Flushed counters from Postgres plus whatever is still pending in Redis
+async fn live_clicks(
+    state: &AppState,
+    short_code: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<LiveClicks, sqlx::Error> {
+    let flushed: Vec<(NaiveDate, i64)> =
+        sqlx::query_as("SELECT day, clicks FROM link_click_counters WHERE short_code = $1")
+            .bind(short_code)
+            .fetch_all(state.db.reader())
+            .await?;
+    let mut days: BTreeMap<NaiveDate, i64> = flushed.into_iter().collect();
+
+    // Counters are flushed within seconds, so only the requested days and the
+    // last two can still hold unflushed clicks
+    let today = Utc::now().date_naive();
+    let mut pending_days: BTreeSet<NaiveDate> = from
+        .date_naive()
+        .iter_days()
+        .take_while(|day| *day <= to.date_naive())
+        .collect();
+    pending_days.insert(today);
+    pending_days.extend(today.pred_opt());
+    let pending_days: Vec<NaiveDate> = pending_days.into_iter().collect();
+
+    match state.redis.pending_clicks(short_code, &pending_days).await {
+        Ok(pending) => {
+            for (day, count) in pending_days.into_iter().zip(pending) {
+                if count > 0 {
+                    *days.entry(day).or_default() += count;
+                }
+            }
+        }
+        Err(e) => tracing::warn!("Live click counters unavailable: {}", e),
+    }
+
+    Ok(LiveClicks {
+        total_clicks: days.values().sum(),
+        today_clicks: days.get(&today).copied().unwrap_or(0),
+        daily: days
+            .range(from.date_naive()..=to.date_naive())
+            .map(|(day, clicks)| DayCount {
+                day: *day,
+                clicks: *clicks,
+            })
+            .collect(),
+    })
+}
+
+async fn top_values(
+    pool: &PgPool,
+    short_code: &str,
+    dimension: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<TopValue>, sqlx::Error> {
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT value, sum(clicks)::bigint FROM click_rollups_dimension
+         WHERE short_code = $1 AND dimension = $2
+         AND day >= $3 AND day <= $4
+         GROUP BY value
+         ORDER BY 2 DESC, value
+         LIMIT $5",
+    )
+    .bind(short_code)
+    .bind(dimension)
+    .bind(from.date_naive())
+    .bind(to.date_naive())
+    .bind(TOP_LIMIT)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(value, clicks)| TopValue { value, clicks })
+        .collect())
+}